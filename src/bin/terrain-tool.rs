@@ -0,0 +1,261 @@
+//! Offline terrain baking, using the same terrain code as the game.
+//! The arguments are processed in order, so filters and exports can be chained, e.g.
+//! `terrain-tool --noise 512 512 --thermal 20 --erode 100000 --normalize 0 1 --stats --save-png out.png --save-glb out.glb`
+use std::path::Path;
+use std::sync::Arc;
+use rust_bevy_fun::erosion::{erode_hydraulic, erode_thermal, ErosionMasks, HydraulicErosion, ThermalErosion};
+use rust_bevy_fun::export::{save_elevation_map_png16, save_elevation_map_r32, write_glb, write_obj};
+use rust_bevy_fun::mesh::{create_mesh, generate_noisemap, load_elevation_map, noisemap_to_elevation_map, EdgeMode, ElevationMap, Lod};
+use rust_bevy_fun::source::ImageSource;
+use rust_bevy_fun::tiled::{write_tiled_elevation_map, SampleFormat, TiledElevationMap};
+
+const USAGE: &str = "\
+usage: terrain-tool [option]...
+Options are processed in order, every filter and export works on the current elevation map.
+
+settings (apply to the following options):
+  --seed <n>                  seed of the noise and the hydraulic erosion (default 0)
+  --max-height <h>            height of the brightest pixel when loading and saving images (default 1)
+  --extent <e>                size of exported meshes in world units (default 200)
+  --intensity <i>             vertical scale of exported meshes and of the terrain in the game (default 4)
+  --texels-per-unit <t>       density of the elevation map in the game, like the game's option (default 5.12)
+  --stride <s>                use every s-th elevation value as vertex of exported meshes (default 1)
+  --edge-mode <wrap|clamp|mirror|sea:<level>>
+                              sampling of the map outside of its borders (default wrap)
+
+input:
+  --load <file>               load an elevation map (PNG, TIFF, .r16, .r32, tiled .thm)
+  --noise <width> <depth> <frequency> <lacunarity> <octaves>
+                              generate a seamless noise map, like the game's fallback terrain
+
+filters:
+  --thermal <iterations>      thermal erosion with the default talus angle,
+                              applying to the terrain as in the game (see --texels-per-unit and --intensity)
+  --erode <droplets>          hydraulic erosion
+  --blur <sigma>              gaussian blur
+  --normalize <min> <max>     stretch the elevation values to the range
+  --clamp <min> <max>         limit the elevation values to the range
+  --invert                    turn the terrain upside down
+  --terrace <step> <smoothness>
+  --resample <width> <depth>  resample to a new resolution
+
+output:
+  --stats                     print statistics of the elevation map
+  --save-png <file>           save as 16-bit PNG
+  --save-r32 <file>           save as raw 32-bit floats
+  --save-tiled <file> <tile-size> <f32|u16>
+                              save as tiled elevation map (.thm), which the game pages in with --terrain tiled
+  --save-masks <prefix>       save sediment and flow of the last hydraulic erosion as <prefix>-sediment.png and <prefix>-flow.png
+  --save-obj <file>           save the mesh as Wavefront OBJ
+  --save-glb <file>           save the mesh as binary glTF";
+
+/// Settings and the current elevation map, while processing the arguments
+struct Tool {
+    seed: u64,
+    max_height: f64,
+    extent: f64,
+    intensity: f32,
+    texels_per_unit: f64,
+    stride: usize,
+    edge_mode: EdgeMode,
+    map: Option<ElevationMap>,
+    masks: Option<ErosionMasks>,
+}
+
+impl Default for Tool {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            max_height: 1.0,
+            extent: 200.0,
+            intensity: 4.0,
+            texels_per_unit: 5.12,
+            stride: 1,
+            edge_mode: EdgeMode::default(),
+            map: None,
+            masks: None,
+        }
+    }
+}
+
+impl Tool {
+    fn map(&mut self) -> Result<&mut ElevationMap, String> {
+        self.map.as_mut().ok_or_else(|| "no elevation map, use --load or --noise first".to_string())
+    }
+
+    /// Applies an operation to the current elevation map
+    fn apply(&mut self, operation: impl FnOnce(ElevationMap) -> ElevationMap) -> Result<(), String> {
+        let map = self.map.take().ok_or_else(|| "no elevation map, use --load or --noise first".to_string())?;
+        self.map = Some(operation(map));
+        Ok(())
+    }
+
+    /// Creates a single mesh covering the whole elevation map
+    fn mesh(&mut self) -> Result<bevy::prelude::Mesh, String> {
+        let (extent, intensity, stride) = (self.extent, self.intensity, self.stride);
+        let map = self.map()?.clone();
+        // the last texels are the far border of the mesh, so it doesn't sample beyond the map
+        let (width, depth) = map.size();
+        let cells = (width.saturating_sub(1).max(1), depth.saturating_sub(1).max(1));
+        let source = ImageSource::new(Arc::new(map), (extent / cells.0 as f64, extent / cells.1 as f64));
+        Ok(create_mesh(extent, (0, 0), cells, &source, intensity, Lod { stride, skirt_depth: 0.0 }, (extent, extent)))
+    }
+
+    fn print_stats(&mut self) -> Result<(), String> {
+        let map = self.map()?;
+        let (width, depth) = map.size();
+        let (min, max) = map.range();
+        let count = map.values().len() as f64;
+        let mean = map.values().iter().sum::<f64>() / count;
+        let deviation = (map.values().iter().map(|value| (value - mean).powi(2)).sum::<f64>() / count).sqrt();
+        println!("size: {width}x{depth}, edge mode: {:?}", map.edge_mode());
+        println!("min: {min:.6}, max: {max:.6}, mean: {mean:.6}, standard deviation: {deviation:.6}");
+        Ok(())
+    }
+
+    /// Processes one option, taking its values from `args`
+    fn process(&mut self, option: &str, args: &mut impl Iterator<Item = String>) -> Result<(), String> {
+        let mut value = |name: &str| args.next().ok_or_else(|| format!("{option} needs a value for <{name}>"));
+        match option {
+            "--seed" => self.seed = parse(&value("n")?)?,
+            "--max-height" => self.max_height = parse(&value("h")?)?,
+            "--extent" => self.extent = parse(&value("e")?)?,
+            "--intensity" => self.intensity = parse(&value("i")?)?,
+            "--texels-per-unit" => {
+                let texels_per_unit: f64 = parse(&value("t")?)?;
+                if texels_per_unit <= 0.0 || !texels_per_unit.is_finite() {
+                    return Err(format!("invalid value {texels_per_unit} for {option} (must be positive)"));
+                }
+                self.texels_per_unit = texels_per_unit;
+            },
+            "--stride" => self.stride = parse::<usize>(&value("s")?)?.max(1),
+            "--edge-mode" => {
+                self.edge_mode = parse_edge_mode(&value("mode")?)?;
+                let edge_mode = self.edge_mode;
+                if let Some(map) = self.map.take() {
+                    self.map = Some(map.with_edge_mode(edge_mode));
+                }
+            },
+            "--load" => {
+                let file = value("file")?;
+                let map = if file.ends_with(".thm") {
+                    TiledElevationMap::open(&file, usize::MAX).map(|map| map.to_elevation_map())
+                } else {
+                    load_elevation_map(&file, self.max_height)
+                };
+                let map = map.map_err(|err| format!("{file}: {err}"))?;
+                self.map = Some(map.with_edge_mode(self.edge_mode));
+            },
+            "--noise" => {
+                let (width, depth) = (parse(&value("width")?)?, parse(&value("depth")?)?);
+                let (frequency, lacunarity, octaves) = (parse(&value("frequency")?)?, parse(&value("lacunarity")?)?, parse(&value("octaves")?)?);
+                let noise_seed = (self.seed ^ (self.seed >> 32)) as u32;
+                let noisemap = generate_noisemap(noise_seed, self.extent, width, depth, frequency, lacunarity, octaves, None);
+                self.map = Some(noisemap_to_elevation_map(&noisemap, self.max_height).with_edge_mode(self.edge_mode));
+            },
+            "--thermal" => {
+                // the talus angle applies to the terrain in the game, like the game's thermal erosion
+                let texel_size = 1.0 / self.texels_per_unit / self.intensity as f64;
+                let params = ThermalErosion { iterations: parse(&value("iterations")?)?, texel_size, ..Default::default() };
+                erode_thermal(self.map()?, &params);
+            },
+            "--erode" => {
+                let params = HydraulicErosion { droplets: parse(&value("droplets")?)?, ..Default::default() };
+                let seed = self.seed;
+                self.masks = erode_hydraulic(self.map()?, &params, seed, true);
+            },
+            "--blur" => {
+                let sigma = parse(&value("sigma")?)?;
+                self.apply(|map| map.blur(sigma))?;
+            },
+            "--normalize" => {
+                let (min, max) = (parse(&value("min")?)?, parse(&value("max")?)?);
+                self.apply(|map| map.normalize(min, max))?;
+            },
+            "--clamp" => {
+                let (min, max) = (parse(&value("min")?)?, parse(&value("max")?)?);
+                self.apply(|map| map.clamp(min, max))?;
+            },
+            "--invert" => self.apply(ElevationMap::invert)?,
+            "--terrace" => {
+                let (step, smoothness) = (parse(&value("step")?)?, parse(&value("smoothness")?)?);
+                self.apply(|map| map.terrace(step, smoothness))?;
+            },
+            "--resample" => {
+                let (width, depth) = (parse(&value("width")?)?, parse(&value("depth")?)?);
+                self.apply(|map| map.resample(width, depth))?;
+            },
+            "--stats" => self.print_stats()?,
+            "--save-png" => {
+                let (file, max_height) = (value("file")?, self.max_height);
+                save_elevation_map_png16(self.map()?, &file, max_height).map_err(|err| format!("{file}: {err}"))?;
+            },
+            "--save-r32" => {
+                let (file, max_height) = (value("file")?, self.max_height);
+                save_elevation_map_r32(self.map()?, &file, max_height).map_err(|err| format!("{file}: {err}"))?;
+            },
+            "--save-tiled" => {
+                let (file, tile_size) = (value("file")?, parse(&value("tile-size")?)?);
+                let format = match value("format")?.as_str() {
+                    "f32" => SampleFormat::F32,
+                    "u16" => SampleFormat::U16,
+                    format => return Err(format!("unknown sample format {format}, expected f32 or u16")),
+                };
+                write_tiled_elevation_map(self.map()?, &file, tile_size, format).map_err(|err| format!("{file}: {err}"))?;
+            },
+            "--save-masks" => {
+                let prefix = value("prefix")?;
+                let masks = self.masks.as_ref().ok_or("no erosion masks, use --erode first")?;
+                for (name, mask) in [("sediment", &masks.sediment), ("flow", &masks.flow)] {
+                    let file = format!("{prefix}-{name}.png");
+                    let max = mask.range().1.max(f64::EPSILON);
+                    save_elevation_map_png16(mask, &file, max).map_err(|err| format!("{file}: {err}"))?;
+                }
+            },
+            "--save-obj" | "--save-glb" => {
+                let file = value("file")?;
+                let mesh = self.mesh()?;
+                let name = Path::new(&file).file_stem().map_or("terrain".into(), |stem| stem.to_string_lossy().into_owned());
+                let writer = std::fs::File::create(&file).map_err(|err| format!("{file}: {err}"))?;
+                let meshes = [(name, &mesh)];
+                let result = if option == "--save-obj" { write_obj(&meshes, writer) } else { write_glb(&meshes, writer) };
+                result.map_err(|err| format!("{file}: {err}"))?;
+            },
+            "--help" | "-h" => println!("{USAGE}"),
+            _ => return Err(format!("unknown option {option}")),
+        }
+        Ok(())
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> Result<T, String>
+where T::Err: std::fmt::Display {
+    value.parse().map_err(|err| format!("invalid value {value}: {err}"))
+}
+
+fn parse_edge_mode(value: &str) -> Result<EdgeMode, String> {
+    match value {
+        "wrap" => Ok(EdgeMode::Wrap),
+        "clamp" => Ok(EdgeMode::Clamp),
+        "mirror" => Ok(EdgeMode::Mirror),
+        _ => match value.strip_prefix("sea:") {
+            Some(level) => Ok(EdgeMode::SeaLevel(parse(level)?)),
+            None => Err(format!("unknown edge mode {value}")),
+        },
+    }
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    if args.len() == 0 {
+        println!("{USAGE}");
+        return;
+    }
+    let mut tool = Tool::default();
+    while let Some(option) = args.next() {
+        if let Err(err) = tool.process(&option, &mut args) {
+            eprintln!("terrain-tool: {err}");
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+use noise::{NoiseFn, Perlin};
+use crate::mesh::ElevationMap;
+
+/// What a sculpting brush does with the elevation values below it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BrushKind {
+    /// Lifts the terrain.
+    #[default]
+    Raise,
+    /// Digs into the terrain.
+    Lower,
+    /// Moves the elevation values towards the average of their neighbours.
+    Smooth,
+    /// Moves the elevation values towards the elevation at the center of the brush.
+    Flatten,
+    /// Adds noise to the terrain.
+    Noise,
+}
+
+impl BrushKind {
+    pub const ALL: [BrushKind; 5] = [BrushKind::Raise, BrushKind::Lower, BrushKind::Smooth, BrushKind::Flatten, BrushKind::Noise];
+}
+
+/// A circular sculpting brush working on an `ElevationMap`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Brush {
+    pub kind: BrushKind,
+    /// radius in texels
+    pub radius: f64,
+    /// change of the elevation values per second (in the units of the map) at full weight,
+    /// for smooth and flatten the fraction of the distance to the target per second
+    pub strength: f64,
+    /// fraction of the radius (from the border inwards) over which the brush fades out,
+    /// 0.0 is a hard edge, 1.0 fades out from the center
+    pub falloff: f64,
+    /// seed of the noise brush
+    pub seed: u32,
+}
+
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            kind: BrushKind::default(),
+            radius: 20.0,
+            strength: 1.0,
+            falloff: 0.5,
+            seed: 0,
+        }
+    }
+}
+
+impl Brush {
+    /// features of the noise brush are this many texels apart
+    const NOISE_SCALE: f64 = 8.0;
+
+    /// Returns the weight (0.0 to 1.0) of the brush at the given distance (in texels) from its center.
+    pub fn weight(&self, distance: f64) -> f64 {
+        if distance >= self.radius {
+            return 0.0;
+        }
+        let fade = self.falloff.clamp(0.0, 1.0) * self.radius;
+        if fade <= 0.0 {
+            return 1.0;
+        }
+        let t = ((self.radius - distance) / fade).min(1.0);
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    /// Applies the brush for `delta_seconds` around `center` (in texels, may be outside of the map).
+    /// Texels outside of the map are resolved by its edge mode, texels beyond a sea level border are left out.
+    /// Returns the index (x + y * width) and previous value of every changed texel.
+    pub fn apply(&self, map: &mut ElevationMap, center: (f64, f64), delta_seconds: f64) -> Vec<(usize, f64)> {
+        let width = map.size().0;
+        let extent = self.radius.ceil() as isize;
+        let (center_x, center_y) = (center.0.round() as isize, center.1.round() as isize);
+        let flatten_target = map.sample(center.0, center.1);
+        let noise = Perlin::new(self.seed);
+
+        // calculate all new values first, so smoothing doesn't see its own changes
+        let mut visited = HashSet::new();
+        let mut updates = Vec::new();
+        for y in center_y - extent..=center_y + extent {
+            for x in center_x - extent..=center_x + extent {
+                let distance = ((x as f64 - center.0).powi(2) + (y as f64 - center.1).powi(2)).sqrt();
+                let amount = self.weight(distance) * self.strength * delta_seconds;
+                if amount <= 0.0 {
+                    continue;
+                }
+                let Some((map_x, map_y)) = map.resolve(x, y) else { continue };
+                let index = map_x + map_y * width;
+                if !visited.insert(index) {
+                    continue;
+                }
+                let value = map.get_value(x, y);
+                let new_value = match self.kind {
+                    BrushKind::Raise => value + amount,
+                    BrushKind::Lower => value - amount,
+                    BrushKind::Smooth => {
+                        let average = (-1..=1).flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+                            .map(|(dx, dy)| map.get_value(x + dx, y + dy))
+                            .sum::<f64>() / 9.0;
+                        value + (average - value) * amount.min(1.0)
+                    },
+                    BrushKind::Flatten => value + (flatten_target - value) * amount.min(1.0),
+                    BrushKind::Noise => {
+                        let point = [map_x as f64 / Self::NOISE_SCALE, map_y as f64 / Self::NOISE_SCALE];
+                        value + noise.get(point) * amount
+                    },
+                };
+                updates.push((index, value, new_value));
+            }
+        }
+
+        let values = map.values_mut();
+        updates.into_iter()
+            .filter(|(_, value, new_value)| value != new_value)
+            .map(|(index, value, new_value)| {
+                values[index] = new_value;
+                (index, value)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::EdgeMode;
+
+    fn flat_map(value: f64) -> ElevationMap {
+        ElevationMap::new_with_data(32, 32, vec![value; 32 * 32]).with_edge_mode(EdgeMode::Clamp)
+    }
+
+    #[test]
+    fn raise_and_lower_fade_out_to_the_radius() {
+        let brush = Brush { radius: 5.0, strength: 2.0, falloff: 1.0, ..Default::default() };
+        let mut map = flat_map(1.0);
+        let changes = brush.apply(&mut map, (16.0, 16.0), 0.5);
+        assert!((map.get_value(16, 16) - 2.0).abs() < 1e-9);
+        assert!(map.get_value(18, 16) > 1.0 && map.get_value(18, 16) < map.get_value(17, 16));
+        assert_eq!(map.get_value(21, 16), 1.0);
+        assert_eq!(map.get_value(16, 11), 1.0);
+        // every changed texel is reported with its previous value
+        assert!(changes.iter().all(|&(_, old)| old == 1.0));
+        assert_eq!(changes.len(), map.values().iter().filter(|&&value| value != 1.0).count());
+
+        let lower = Brush { kind: BrushKind::Lower, ..brush };
+        lower.apply(&mut map, (16.0, 16.0), 0.5);
+        assert!(map.values().iter().all(|value| (value - 1.0).abs() < 1e-9));
+    }
+
+    #[test]
+    fn smooth_and_flatten_move_towards_their_target() {
+        let mut map = flat_map(0.0);
+        map._set_value(16, 16, 9.0);
+        let smooth = Brush { kind: BrushKind::Smooth, radius: 3.0, strength: 1.0, falloff: 0.0, ..Default::default() };
+        smooth.apply(&mut map, (16.0, 16.0), 1.0);
+        assert!((map.get_value(16, 16) - 1.0).abs() < 1e-9);
+        assert!((map.get_value(15, 16) - 1.0).abs() < 1e-9);
+
+        let mut map = ElevationMap::new_with_data(32, 32, (0..32 * 32).map(|i| (i % 32) as f64).collect());
+        let flatten = Brush { kind: BrushKind::Flatten, radius: 4.0, strength: 1.0, falloff: 0.0, ..Default::default() };
+        flatten.apply(&mut map, (10.0, 10.0), 1.0);
+        assert_eq!(map.get_value(8, 10), 10.0);
+        assert_eq!(map.get_value(12, 12), 10.0);
+        assert_eq!(map.get_value(15, 10), 15.0);
+    }
+
+    #[test]
+    fn noise_is_deterministic_and_edges_are_resolved() {
+        let brush = Brush { kind: BrushKind::Noise, radius: 6.0, ..Default::default() };
+        let (mut a, mut b) = (flat_map(0.0), flat_map(0.0));
+        brush.apply(&mut a, (10.3, 12.7), 1.0);
+        brush.apply(&mut b, (10.3, 12.7), 1.0);
+        assert_eq!(a.values(), b.values());
+        assert!(a.values().iter().any(|&value| value != 0.0));
+
+        // a wrapped map is changed on the opposite border as well
+        let mut map = flat_map(0.0).with_edge_mode(EdgeMode::Wrap);
+        let raise = Brush { radius: 3.0, ..Default::default() };
+        raise.apply(&mut map, (0.0, 16.0), 1.0);
+        assert!(map.get_value(31, 16) > 0.0);
+        // nothing beyond the sea level border is changed
+        let mut map = flat_map(0.0).with_edge_mode(EdgeMode::SeaLevel(0.0));
+        let changes = raise.apply(&mut map, (0.0, 16.0), 1.0);
+        assert!(changes.iter().all(|&(index, _)| index % 32 < 3));
+    }
+}
@@ -0,0 +1,170 @@
+use std::ops::RangeInclusive;
+use bevy::prelude::Vec3;
+use serde::{Deserialize, Serialize};
+
+/// Signed coordinates [x][z] of a terrain chunk, chunk (0, 0) starts at the world origin
+/// and extends towards +x and +z.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ChunkCoord(pub i32, pub i32);
+
+impl ChunkCoord {
+    /// Returns the coordinates of the chunk shifted by (dx, dz) chunks.
+    pub fn offset(self, dx: i32, dz: i32) -> Self {
+        ChunkCoord(self.0 + dx, self.1 + dz)
+    }
+
+    /// Returns the distance in chunks along the farther axis (Chebyshev distance).
+    pub fn distance(self, other: ChunkCoord) -> i32 {
+        (self.0 - other.0).abs().max((self.1 - other.1).abs())
+    }
+}
+
+impl std::fmt::Display for ChunkCoord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}][{}]", self.0, self.1)
+    }
+}
+
+/// Layout of the terrain chunks: their size in the world, the resolution of their meshes and the density of the heightmap.
+/// The three settings are independent, so a chunk can sample any sub-region of a large heightmap.
+/// Converts between world positions, chunk coordinates, mesh cells and (unbounded) heightmap texels,
+/// texel (0, 0) and cell (0, 0) are at the world origin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChunkLayout {
+    /// size (x and z) of a chunk in world units
+    pub chunk_size: f64,
+    /// number of mesh cells along each side of a chunk (at full level of detail)
+    pub resolution: usize,
+    /// number of heightmap texels per world unit
+    pub texels_per_unit: f64,
+}
+
+impl ChunkLayout {
+    pub fn new(chunk_size: f64, resolution: usize, texels_per_unit: f64) -> Self {
+        Self { chunk_size, resolution, texels_per_unit }
+    }
+
+    /// Returns the size of one texel in world units.
+    pub fn texel_size(&self) -> f64 {
+        1.0 / self.texels_per_unit
+    }
+
+    /// Returns the size of one mesh cell in world units.
+    pub fn cell_size(&self) -> f64 {
+        self.chunk_size / self.resolution as f64
+    }
+
+    /// Returns the chunk containing the world position (x, z).
+    pub fn world_to_chunk(&self, x: f64, z: f64) -> ChunkCoord {
+        ChunkCoord((x / self.chunk_size).floor() as i32, (z / self.chunk_size).floor() as i32)
+    }
+
+    /// Returns the chunk containing the world position, ignoring its height.
+    pub fn position_to_chunk(&self, position: Vec3) -> ChunkCoord {
+        self.world_to_chunk(position.x as f64, position.z as f64)
+    }
+
+    /// Returns the world position (x, z) of the corner of the chunk at its lowest x and z.
+    pub fn chunk_to_world(&self, chunk: ChunkCoord) -> (f64, f64) {
+        (chunk.0 as f64 * self.chunk_size, chunk.1 as f64 * self.chunk_size)
+    }
+
+    /// Returns the first mesh cell (x, z) of the chunk, the mesh position of `create_mesh`.
+    pub fn chunk_to_cell(&self, chunk: ChunkCoord) -> (isize, isize) {
+        (chunk.0 as isize * self.resolution as isize, chunk.1 as isize * self.resolution as isize)
+    }
+
+    /// Returns the chunk containing the texel (x, z).
+    pub fn texel_to_chunk(&self, x: isize, z: isize) -> ChunkCoord {
+        let (x, z) = self.texel_to_world(x, z);
+        self.world_to_chunk(x, z)
+    }
+
+    /// Returns the texels (x and z) whose values affect the mesh of the chunk,
+    /// including the neighbours used for its border normals.
+    pub fn chunk_texels(&self, chunk: ChunkCoord) -> (RangeInclusive<isize>, RangeInclusive<isize>) {
+        let (x, z) = self.chunk_to_world(chunk);
+        let margin = self.cell_size();
+        let texels = |min: f64| {
+            let first = ((min - margin) * self.texels_per_unit).floor() as isize;
+            let last = ((min + self.chunk_size + margin) * self.texels_per_unit).floor() as isize + 1;
+            first..=last
+        };
+        (texels(x), texels(z))
+    }
+
+    /// Returns the continuous texel position of the world position (x, z), e.g. for bilinear sampling.
+    pub fn world_to_texel(&self, x: f64, z: f64) -> (f64, f64) {
+        (x * self.texels_per_unit, z * self.texels_per_unit)
+    }
+
+    /// Returns the texel (x, z) containing the world position (x, z).
+    pub fn world_to_texel_index(&self, x: f64, z: f64) -> (isize, isize) {
+        let (x, z) = self.world_to_texel(x, z);
+        (x.floor() as isize, z.floor() as isize)
+    }
+
+    /// Returns the world position (x, z) of the texel (x, z).
+    pub fn texel_to_world(&self, x: isize, z: isize) -> (f64, f64) {
+        (x as f64 / self.texels_per_unit, z as f64 / self.texels_per_unit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // chunks of 200 world units with 50 cells, covering 100 texels
+    const LAYOUT: ChunkLayout = ChunkLayout { chunk_size: 200.0, resolution: 50, texels_per_unit: 0.5 };
+
+    #[test]
+    fn world_to_chunk_on_both_sides_of_the_origin() {
+        assert_eq!(LAYOUT.world_to_chunk(0.0, 0.0), ChunkCoord(0, 0));
+        assert_eq!(LAYOUT.world_to_chunk(199.9, 0.1), ChunkCoord(0, 0));
+        assert_eq!(LAYOUT.world_to_chunk(200.0, 400.0), ChunkCoord(1, 2));
+        assert_eq!(LAYOUT.world_to_chunk(-0.1, -0.1), ChunkCoord(-1, -1));
+        assert_eq!(LAYOUT.world_to_chunk(-200.0, -200.1), ChunkCoord(-1, -2));
+        assert_eq!(LAYOUT.position_to_chunk(Vec3::new(-150.0, 30.0, 250.0)), ChunkCoord(-1, 1));
+        for chunk in [ChunkCoord(-3, 2), ChunkCoord(0, -1), ChunkCoord(5, 0)] {
+            let (x, z) = LAYOUT.chunk_to_world(chunk);
+            assert_eq!(LAYOUT.world_to_chunk(x, z), chunk);
+            assert_eq!(LAYOUT.world_to_chunk(x + 199.0, z + 199.0), chunk);
+            assert_eq!(LAYOUT.world_to_chunk(x - 1.0, z), chunk.offset(-1, 0));
+        }
+    }
+
+    #[test]
+    fn chunk_to_texel_on_both_sides_of_the_origin() {
+        assert_eq!(LAYOUT.chunk_to_cell(ChunkCoord(2, -3)), (100, -150));
+        assert_eq!(LAYOUT.texel_to_chunk(0, 0), ChunkCoord(0, 0));
+        assert_eq!(LAYOUT.texel_to_chunk(99, 99), ChunkCoord(0, 0));
+        assert_eq!(LAYOUT.texel_to_chunk(100, 200), ChunkCoord(1, 2));
+        assert_eq!(LAYOUT.texel_to_chunk(-1, -1), ChunkCoord(-1, -1));
+        assert_eq!(LAYOUT.texel_to_chunk(-100, -101), ChunkCoord(-1, -2));
+        for chunk in [ChunkCoord(-2, -2), ChunkCoord(-1, 3), ChunkCoord(4, 0)] {
+            let (x, z) = LAYOUT.chunk_texels(chunk);
+            // one cell (2 texels) and the texel for interpolation around the 100 texels of the chunk
+            assert_eq!((x.start() + 2, x.end() - 3), (chunk.0 as isize * 100, chunk.0 as isize * 100 + 100));
+            assert_eq!((z.start() + 2, z.end() - 3), (chunk.1 as isize * 100, chunk.1 as isize * 100 + 100));
+            assert_eq!(LAYOUT.texel_to_chunk(x.start() + 2, z.start() + 2), chunk);
+            assert_eq!(LAYOUT.texel_to_chunk(x.start() + 1, z.start() + 1), chunk.offset(-1, -1));
+        }
+    }
+
+    #[test]
+    fn world_to_texel_on_both_sides_of_the_origin() {
+        assert_eq!((LAYOUT.texel_size(), LAYOUT.cell_size()), (2.0, 4.0));
+        assert_eq!(LAYOUT.world_to_texel(3.0, -6.0), (1.5, -3.0));
+        assert_eq!(LAYOUT.world_to_texel_index(3.0, -5.0), (1, -3));
+        assert_eq!(LAYOUT.world_to_texel_index(-0.5, 0.5), (-1, 0));
+        assert_eq!(LAYOUT.texel_to_world(-3, 7), (-6.0, 14.0));
+        for (x, z) in [(-7, -3), (0, 0), (12, -40)] {
+            let (world_x, world_z) = LAYOUT.texel_to_world(x, z);
+            assert_eq!(LAYOUT.world_to_texel_index(world_x + 0.1, world_z + 0.1), (x, z));
+            // texels and chunks agree about the chunk borders
+            assert_eq!(LAYOUT.texel_to_chunk(x, z), LAYOUT.world_to_chunk(world_x, world_z));
+        }
+        assert_eq!(ChunkCoord(-1, 2).distance(ChunkCoord(2, 0)), 3);
+        assert_eq!(ChunkCoord(-1, 2).to_string(), "[-1][2]");
+    }
+}
@@ -0,0 +1,39 @@
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use rust_bevy_fun::raycast::{raycast, TerrainHit};
+use crate::CameraControl;
+use crate::Terrain;
+
+/// Finds the point of the terrain under the mouse cursor every frame, see `CursorHit`.
+pub struct CursorPlugin;
+
+impl Plugin for CursorPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<CursorHit>()
+            .add_systems(Update, update_cursor_hit);
+    }
+}
+
+/// Where the ray from the `CameraControl` camera through the mouse cursor hits the elevation map,
+/// None if the cursor isn't over the terrain (or the terrain has no elevation map).
+#[derive(Resource, Default)]
+pub struct CursorHit(pub Option<TerrainHit>);
+impl CursorHit {
+    const MAX_RAY_DISTANCE: f32 = 1000.0;
+}
+
+pub fn update_cursor_hit(
+    mut cursor_hit: ResMut<CursorHit>,
+    terrain: Res<Terrain>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform), With<CameraControl>>,
+) {
+    let ray = window_query.get_single().ok()
+        .and_then(|window| window.cursor_position())
+        .zip(camera_query.get_single().ok())
+        .and_then(|(cursor, (camera, camera_transform))| camera.viewport_to_world(camera_transform, cursor));
+    cursor_hit.0 = ray.zip(terrain.heightmap()).and_then(|(ray, map)| {
+        raycast(map, &terrain.layout(), terrain.intensity, ray.origin, ray.direction, CursorHit::MAX_RAY_DISTANCE)
+    });
+}
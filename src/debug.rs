@@ -9,10 +9,12 @@ use bevy_egui::egui;
 use bevy::prelude::*;
 use bevy_rapier3d::prelude::*;
 use crate::helper::format_vec3f;
-use crate::CameraControl;
-use crate::MovableBall;
-use crate::MovableCube;
+use crate::BallFilter;
+use crate::cursor::CursorHit;
+use crate::Ground;
 use crate::Terrain;
+use crate::TerrainLoadError;
+use crate::WorldSeed;
 
 pub struct DebugTextPlugin;
 
@@ -24,36 +26,63 @@ impl Plugin for DebugTextPlugin {
     }
 }
 
-#[derive(Resource)]
+#[derive(Resource, Default)]
 pub struct DebugTextState {
     worldinspector: bool,
 }
 
-impl Default for DebugTextState {
-    fn default() -> Self {
-        Self {
-            worldinspector: false,
-        }
-    }
-}
-
 // https://whoisryosuke.com/blog/2023/getting-started-with-egui-in-rust
+#[allow(clippy::too_many_arguments)]
 fn debug_ui_system(mut contexts: EguiContexts,
     mut text_state: ResMut<DebugTextState>,
     terrain: Res<Terrain>,
-    ball_query: Query<(&Transform, &Velocity), (With<MovableBall>,Without<MovableCube>,Without<CameraControl>)>,) {
+    ground: Ground,
+    cursor_hit: Res<CursorHit>,
+    load_error: Option<Res<TerrainLoadError>>,
+    seed: Res<WorldSeed>,
+    ball_query: Query<(&Transform, &Velocity), BallFilter>,) {
     egui::Window::new("Debug output").show(contexts.ctx_mut(), |ui| {
         let (ball_transform, velocity) = ball_query.single();
         ui.horizontal(|ui| {
             ui.label(format!("LOC:{}", format_vec3f(ball_transform.translation)));
             ui.label(format!("VEL:{}", format_vec3f(velocity.linvel)));
             ui.label(format!("ROT:{}", format_vec3f(ball_transform.rotation.xyz())));
-            let (width, depth) = terrain.mesh_size;
-            let x = (ball_transform.translation.x / terrain.size as f32) as i32 % width as i32;
-            let y = (ball_transform.translation.z / terrain.size as f32) as i32 % depth as i32;
-            ui.label(format!("MESH:[{x:2.0}]-[{y:2.0}]"));//({:>8.3},{:>8.3},{:>8.3})"
+            let chunk = terrain.layout().position_to_chunk(ball_transform.translation);
+            ui.label(format!("MESH:[{:2}]-[{:2}]", chunk.0, chunk.1));//({:>8.3},{:>8.3},{:>8.3})"
             });
         
+        let (x, z) = (ball_transform.translation.x, ball_transform.translation.z);
+        if let (Some(height), Some(normal)) = (ground.height_at(x, z), ground.normal_at(x, z)) {
+            ui.label(format!("GROUND:{height:.2} SLOPE:{:.1}°", normal.angle_between(Vec3::Y).to_degrees()));
+        }
+
+        if let Some(hit) = &cursor_hit.0 {
+            ui.horizontal(|ui| {
+                ui.label(format!("CURSOR:{}", format_vec3f(hit.point)));
+                ui.label(format!("TEXEL:[{}]-[{}]", hit.texel.0, hit.texel.1));
+                ui.label(format!("HEIGHT:{:.2}", hit.point.y));
+                ui.label(format!("MESH:[{:2}]-[{:2}]", hit.chunk.0, hit.chunk.1));
+            });
+        }
+
+        ui.horizontal(|ui| {
+            ui.label(format!("SEED:{}", seed.0));
+            if ui.button("Copy").on_hover_text("copy the world seed to the clipboard, run with --seed <number> to reproduce this world").clicked() {
+                ui.output_mut(|output| output.copied_text = seed.0.to_string());
+            }
+        });
+
+        if let Some(tiled_map) = &terrain.tiled_map {
+            let (tiles, memory) = tiled_map.memory();
+            let megabytes = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+            ui.label(format!("TILES:{tiles} loaded, {:.1} of {:.1} MB", megabytes(memory), megabytes(tiled_map.budget())));
+        }
+
+        if let Some(load_error) = &load_error {
+            ui.separator();
+            ui.colored_label(egui::Color32::RED, format!("Terrain couldn't be loaded, using generated terrain instead: {}", load_error.0));
+        }
+
         ui.separator();
         ui.checkbox(&mut text_state.worldinspector, "WorldInspector")
         // TODO: enable/disable worldinspector
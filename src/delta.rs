@@ -0,0 +1,104 @@
+use serde::{Deserialize, Serialize};
+use crate::mesh::ElevationMap;
+
+/// Changed square tile of a `MapDelta`, with all its values row by row (clipped at the borders of the map).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeltaTile {
+    pub x: usize,
+    pub y: usize,
+    pub values: Vec<f64>,
+}
+
+/// Sparse difference between an edited elevation map and the map it was edited from,
+/// storing only the tiles of `tile_size` texels containing a changed value.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MapDelta {
+    pub size: (usize, usize),
+    pub tile_size: usize,
+    pub tiles: Vec<DeltaTile>,
+}
+
+impl MapDelta {
+    /// Returns the tiles of `edited` which differ from `base`, or `None` if the maps have different sizes.
+    pub fn between(base: &ElevationMap, edited: &ElevationMap, tile_size: usize) -> Option<Self> {
+        if base.size() != edited.size() || tile_size == 0 {
+            return None;
+        }
+        let (width, height) = base.size();
+        let mut tiles = Vec::new();
+        for tile_y in (0..height).step_by(tile_size) {
+            for tile_x in (0..width).step_by(tile_size) {
+                let rows = tile_y..(tile_y + tile_size).min(height);
+                let columns = tile_x..(tile_x + tile_size).min(width);
+                let indices = || rows.clone().flat_map(|y| columns.clone().map(move |x| x + y * width));
+                if indices().any(|i| base.values()[i] != edited.values()[i]) {
+                    let values = indices().map(|i| edited.values()[i]).collect();
+                    tiles.push(DeltaTile { x: tile_x, y: tile_y, values });
+                }
+            }
+        }
+        Some(Self { size: base.size(), tile_size, tiles })
+    }
+
+    /// Returns a copy of `base` with the changed tiles.
+    pub fn apply(&self, base: &ElevationMap) -> Result<ElevationMap, String> {
+        if base.size() != self.size {
+            return Err(format!("the delta is for a map of {:?}, but the map has {:?}", self.size, base.size()));
+        }
+        if self.tile_size == 0 {
+            return Err("the delta has a tile size of 0".to_string());
+        }
+        let (width, height) = self.size;
+        let mut map = base.clone();
+        let values = map.values_mut();
+        for tile in &self.tiles {
+            let invalid = || format!("invalid delta tile at ({}, {})", tile.x, tile.y);
+            if tile.x >= width || tile.y >= height {
+                return Err(invalid());
+            }
+            let (columns, rows) = (tile.x.saturating_add(self.tile_size).min(width) - tile.x, tile.y.saturating_add(self.tile_size).min(height) - tile.y);
+            if tile.values.len() != columns * rows {
+                return Err(invalid());
+            }
+            for (row, tile_row) in tile.values.chunks_exact(columns).enumerate() {
+                let start = tile.x + (tile.y + row) * width;
+                values[start..start + columns].copy_from_slice(tile_row);
+            }
+        }
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delta_stores_only_changed_tiles() {
+        let base = ElevationMap::new_with_data(10, 7, (0..70).map(|i| i as f64).collect());
+        let mut edited = base.clone();
+        edited._set_value(1, 1, -1.0);
+        edited._set_value(9, 6, -2.0);
+        let delta = MapDelta::between(&base, &edited, 4).unwrap();
+        // the tile at the corner is clipped to 2x3 texels
+        assert_eq!(delta.tiles.iter().map(|tile| (tile.x, tile.y, tile.values.len())).collect::<Vec<_>>(), vec![(0, 0, 16), (8, 4, 6)]);
+        assert_eq!(delta.apply(&base).unwrap().values(), edited.values());
+
+        assert!(MapDelta::between(&base, &edited, 4).unwrap().apply(&ElevationMap::new(3, 3)).is_err());
+        assert!(MapDelta::between(&base, &ElevationMap::new(3, 3), 4).is_none());
+        assert!(MapDelta::between(&base, &base, 4).unwrap().tiles.is_empty());
+    }
+
+    #[test]
+    fn broken_deltas_are_rejected() {
+        let base = ElevationMap::new(10, 7);
+        let delta = |tile_size, tiles| MapDelta { size: (10, 7), tile_size, tiles };
+        let tile = |x, y, count| DeltaTile { x, y, values: vec![1.0; count] };
+        assert!(delta(0, vec![]).apply(&base).is_err());
+        assert!(delta(0, vec![tile(0, 0, 0)]).apply(&base).is_err());
+        assert!(delta(usize::MAX, vec![tile(8, 4, 6)]).apply(&base).is_ok());
+        assert!(delta(4, vec![tile(10, 0, 0)]).apply(&base).is_err());
+        assert!(delta(4, vec![tile(8, 4, 16)]).apply(&base).is_err());
+        assert!(delta(4, vec![tile(0, 0, 15)]).apply(&base).is_err());
+    }
+}
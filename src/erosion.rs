@@ -0,0 +1,343 @@
+use bevy::prelude::Image;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use rand::prelude::*;
+use crate::mesh::ElevationMap;
+
+/// Parameters of the particle-based hydraulic erosion, see `erode_hydraulic`.
+/// Heights, capacities and amounts of sediment are in the units of the elevation map.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HydraulicErosion {
+    /// number of simulated water droplets
+    pub droplets: usize,
+    /// maximum number of steps of a droplet before it is dropped
+    pub max_lifetime: usize,
+    /// how much a droplet keeps its direction (0.0) instead of following the slope (1.0)
+    pub inertia: f64,
+    /// sediment a droplet can carry, per unit of speed, water and slope
+    pub sediment_capacity: f64,
+    /// lower limit of the slope used for the capacity, so droplets on flat ground still erode
+    pub min_slope: f64,
+    /// fraction of the free capacity which is eroded per step
+    pub erosion_rate: f64,
+    /// fraction of the surplus sediment which is deposited per step
+    pub deposition_rate: f64,
+    /// fraction of the water which evaporates per step
+    pub evaporation: f64,
+    /// acceleration of droplets running downhill
+    pub gravity: f64,
+    /// radius (in texels) around a droplet which gets eroded
+    pub radius: f64,
+}
+
+impl Default for HydraulicErosion {
+    fn default() -> Self {
+        Self {
+            droplets: 70_000,
+            max_lifetime: 30,
+            inertia: 0.05,
+            sediment_capacity: 4.0,
+            min_slope: 0.01,
+            erosion_rate: 0.3,
+            deposition_rate: 0.3,
+            evaporation: 0.01,
+            gravity: 4.0,
+            radius: 3.0,
+        }
+    }
+}
+
+/// Sediment and water flow of an erosion pass, with the size of the eroded map.
+pub struct ErosionMasks {
+    /// amount of deposited sediment
+    pub sediment: ElevationMap,
+    /// amount of water which flowed over the texels
+    pub flow: ElevationMap,
+}
+
+impl ErosionMasks {
+    fn new(size: (usize, usize)) -> Self {
+        Self {
+            sediment: ElevationMap::new(size.0, size.1),
+            flow: ElevationMap::new(size.0, size.1),
+        }
+    }
+
+    /// Returns a texture with the normalized sediment in the red and the flow in the green channel,
+    /// which can be used by a terrain material.
+    pub fn to_image(&self) -> Image {
+        let (width, height) = self.sediment.size();
+        let normalized = |map: &ElevationMap| {
+            let max = map.values().iter().cloned().fold(f64::EPSILON, f64::max);
+            map.values().iter().map(|&value| (value / max * 255.0).clamp(0.0, 255.0) as u8).collect::<Vec<u8>>()
+        };
+        let (sediment, flow) = (normalized(&self.sediment), normalized(&self.flow));
+        let data = sediment.iter().zip(&flow).flat_map(|(&sediment, &flow)| [sediment, flow, 0, 255]).collect();
+        Image::new(
+            Extent3d { width: width as u32, height: height as u32, depth_or_array_layers: 1 },
+            TextureDimension::D2,
+            data,
+            TextureFormat::Rgba8Unorm,
+        )
+    }
+}
+
+/// Simulates `params.droplets` water droplets running down the elevation map, which erode the terrain
+/// where they speed up and deposit sediment where they slow down.
+/// The droplets start at random positions, so the same `seed` always gives the same result.
+/// Droplets leaving the map are dropped. If `masks` is true, the sediment and flow masks are returned.
+pub fn erode_hydraulic(map: &mut ElevationMap, params: &HydraulicErosion, seed: u64, masks: bool) -> Option<ErosionMasks> {
+    let (width, height) = map.size();
+    let mut masks = masks.then(|| ErosionMasks::new((width, height)));
+    if width < 2 || height < 2 {
+        return masks;
+    }
+    let brush = Brush::new(params.radius);
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    for _ in 0..params.droplets {
+        let mut pos = (rng.gen_range(0.0..(width - 1) as f64), rng.gen_range(0.0..(height - 1) as f64));
+        let mut dir = (0.0, 0.0);
+        let (mut speed, mut water, mut sediment) = (1.0, 1.0, 0.0);
+
+        for _ in 0..params.max_lifetime {
+            let (cell_x, cell_y) = (pos.0 as usize, pos.1 as usize);
+            let (old_height, gradient) = height_and_gradient(map, pos);
+            if let Some(masks) = masks.as_mut() {
+                masks.flow.values_mut()[cell_x + cell_y * width] += water;
+            }
+
+            // follow the slope, keeping some of the previous direction
+            dir = (
+                dir.0 * params.inertia - gradient.0 * (1.0 - params.inertia),
+                dir.1 * params.inertia - gradient.1 * (1.0 - params.inertia),
+            );
+            let length = (dir.0 * dir.0 + dir.1 * dir.1).sqrt();
+            if length < f64::EPSILON {
+                break;
+            }
+            dir = (dir.0 / length, dir.1 / length);
+            let old_pos = pos;
+            pos = (pos.0 + dir.0, pos.1 + dir.1);
+            if pos.0 < 0.0 || pos.1 < 0.0 || pos.0 >= (width - 1) as f64 || pos.1 >= (height - 1) as f64 {
+                break;
+            }
+
+            let delta_height = height_and_gradient(map, pos).0 - old_height;
+            let capacity = (-delta_height).max(params.min_slope) * speed * water * params.sediment_capacity;
+            if sediment > capacity || delta_height > 0.0 {
+                // deposit the surplus sediment, or fill the pit the droplet ran into
+                let amount = if delta_height > 0.0 {
+                    delta_height.min(sediment)
+                } else {
+                    (sediment - capacity) * params.deposition_rate
+                };
+                sediment -= amount;
+                deposit(map, old_pos, amount);
+                if let Some(masks) = masks.as_mut() {
+                    deposit(&mut masks.sediment, old_pos, amount);
+                }
+            } else {
+                // erode the free capacity, but never more than the height difference (no new pits)
+                let amount = ((capacity - sediment) * params.erosion_rate).min(-delta_height);
+                let values = map.values_mut();
+                for (offset_x, offset_y, weight) in &brush.weights {
+                    let (x, y) = (cell_x as isize + offset_x, cell_y as isize + offset_y);
+                    if x < 0 || y < 0 || x >= width as isize || y >= height as isize {
+                        continue;
+                    }
+                    let value = &mut values[x as usize + y as usize * width];
+                    let eroded = (amount * weight).min(*value);
+                    *value -= eroded;
+                    sediment += eroded;
+                }
+            }
+
+            speed = (speed * speed + delta_height * -params.gravity).max(0.0).sqrt();
+            water *= 1.0 - params.evaporation;
+        }
+    }
+    masks
+}
+
+/// Returns the bilinear interpolated height and its gradient at a position within the map
+fn height_and_gradient(map: &ElevationMap, pos: (f64, f64)) -> (f64, (f64, f64)) {
+    let (x, y) = (pos.0 as isize, pos.1 as isize);
+    let (fx, fy) = (pos.0 - x as f64, pos.1 - y as f64);
+    let (nw, ne) = (map.get_value(x, y), map.get_value(x + 1, y));
+    let (sw, se) = (map.get_value(x, y + 1), map.get_value(x + 1, y + 1));
+    let gradient = ((ne - nw) * (1.0 - fy) + (se - sw) * fy, (sw - nw) * (1.0 - fx) + (se - ne) * fx);
+    let height = nw * (1.0 - fx) * (1.0 - fy) + ne * fx * (1.0 - fy) + sw * (1.0 - fx) * fy + se * fx * fy;
+    (height, gradient)
+}
+
+/// Adds the amount of sediment to the four texels around the position, weighted bilinearly
+fn deposit(map: &mut ElevationMap, pos: (f64, f64), amount: f64) {
+    let width = map.size().0;
+    let (x, y) = (pos.0 as usize, pos.1 as usize);
+    let (fx, fy) = (pos.0 - x as f64, pos.1 - y as f64);
+    let values = map.values_mut();
+    values[x + y * width] += amount * (1.0 - fx) * (1.0 - fy);
+    values[x + 1 + y * width] += amount * fx * (1.0 - fy);
+    values[x + (y + 1) * width] += amount * (1.0 - fx) * fy;
+    values[x + 1 + (y + 1) * width] += amount * fx * fy;
+}
+
+/// Texel offsets within the erosion radius, with weights falling off linearly and summing up to 1.0
+struct Brush {
+    weights: Vec<(isize, isize, f64)>,
+}
+
+impl Brush {
+    fn new(radius: f64) -> Self {
+        let radius = radius.max(1.0);
+        let extent = radius.ceil() as isize;
+        let mut weights: Vec<(isize, isize, f64)> = (-extent..=extent)
+            .flat_map(|y| (-extent..=extent).map(move |x| (x, y)))
+            .map(|(x, y)| (x, y, radius - ((x * x + y * y) as f64).sqrt()))
+            .filter(|(_, _, weight)| *weight > 0.0)
+            .collect();
+        let total: f64 = weights.iter().map(|(_, _, weight)| weight).sum();
+        weights.iter_mut().for_each(|(_, _, weight)| *weight /= total);
+        Self { weights }
+    }
+}
+
+/// Parameters of the thermal erosion, see `erode_thermal`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThermalErosion {
+    /// number of weathering passes over the whole map
+    pub iterations: usize,
+    /// steepest stable slope in degrees, material on steeper slopes slides down
+    pub talus_angle: f64,
+    /// fraction of the material above the talus angle moved per iteration
+    pub rate: f64,
+    /// horizontal distance between neighbouring texels, in the units of the elevation values
+    pub texel_size: f64,
+}
+
+impl Default for ThermalErosion {
+    fn default() -> Self {
+        Self {
+            iterations: 20,
+            talus_angle: 35.0,
+            rate: 0.5,
+            texel_size: 1.0,
+        }
+    }
+}
+
+/// Moves material from slopes steeper than the talus angle to the lower neighbours (of the 8 surrounding texels),
+/// which smoothes spikes and cliffs. Neighbours outside of the map are resolved by the edge mode of the map,
+/// material moved beyond the borders of a map with `EdgeMode::SeaLevel` is lost.
+pub fn erode_thermal(map: &mut ElevationMap, params: &ThermalErosion) {
+    const NEIGHBOURS: [(isize, isize); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+    let (width, height) = map.size();
+    let talus = params.talus_angle.to_radians().tan() * params.texel_size;
+    let mut deltas = vec![0.0; width * height];
+
+    for _ in 0..params.iterations {
+        deltas.iter_mut().for_each(|delta| *delta = 0.0);
+        for y in 0..height as isize {
+            for x in 0..width as isize {
+                let value = map.get_value(x, y);
+                // height difference above the talus angle for every neighbour
+                let excess = NEIGHBOURS.map(|(dx, dy)| {
+                    let distance = if dx != 0 && dy != 0 { std::f64::consts::SQRT_2 } else { 1.0 };
+                    (value - map.get_value(x + dx, y + dy) - talus * distance).max(0.0)
+                });
+                let (max_excess, total_excess) = excess.iter().fold((0.0, 0.0), |(max, total): (f64, f64), &e| (max.max(e), total + e));
+                if total_excess <= 0.0 {
+                    continue;
+                }
+                // move half of the largest excess (leveling both texels), shared by all lower neighbours
+                let amount = params.rate * max_excess * 0.5;
+                deltas[x as usize + y as usize * width] -= amount;
+                for ((dx, dy), e) in NEIGHBOURS.iter().zip(excess) {
+                    if let Some((nx, ny)) = map.resolve(x + dx, y + dy) {
+                        deltas[nx + ny * width] += amount * e / total_excess;
+                    }
+                }
+            }
+        }
+        map.values_mut().iter_mut().zip(&deltas).for_each(|(value, delta)| *value += delta);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::EdgeMode;
+
+    fn hill(size: usize) -> ElevationMap {
+        let center = size as f64 / 2.0;
+        let values = (0..size * size)
+            .map(|i| ((i % size) as f64 - center, (i / size) as f64 - center))
+            .map(|(x, y)| (4.0 - (x * x + y * y).sqrt() * 0.1).max(0.0) + ((x * 0.7).sin() * (y * 0.3).cos()) * 0.1)
+            .collect();
+        ElevationMap::new_with_data(size, size, values)
+    }
+
+    #[test]
+    fn hydraulic_erosion_is_deterministic_per_seed() {
+        let params = HydraulicErosion { droplets: 2_000, ..Default::default() };
+        let erode = |seed: u64| {
+            let mut map = hill(64);
+            erode_hydraulic(&mut map, &params, seed, false);
+            map.values().to_vec()
+        };
+        assert_eq!(erode(1), erode(1));
+        assert_ne!(erode(1), erode(2));
+        assert_ne!(erode(1), hill(64).values().to_vec());
+    }
+
+    #[test]
+    fn hydraulic_erosion_moves_sediment_downhill() {
+        let mut map = hill(64);
+        let params = HydraulicErosion { droplets: 5_000, ..Default::default() };
+        let masks = erode_hydraulic(&mut map, &params, 3, true).unwrap();
+        assert!(map.values().iter().all(|value| value.is_finite() && *value >= 0.0));
+        // material is only moved (or carried off the map), never created
+        let total = |map: &ElevationMap| map.values().iter().sum::<f64>();
+        assert!(total(&map) <= total(&hill(64)) + 1e-6);
+        assert!(masks.flow.values().iter().any(|&flow| flow > 0.0));
+        assert!(masks.sediment.values().iter().any(|&sediment| sediment > 0.0));
+        let image = masks.to_image();
+        assert_eq!(image.data.len(), 64 * 64 * 4);
+    }
+
+    #[test]
+    fn thermal_erosion_flattens_spikes() {
+        let mut map = ElevationMap::new(9, 9).with_edge_mode(EdgeMode::Clamp);
+        map._set_value(4, 4, 8.0);
+        let params = ThermalErosion { iterations: 50, talus_angle: 45.0, ..Default::default() };
+        erode_thermal(&mut map, &params);
+        let total: f64 = map.values().iter().sum();
+        assert!((total - 8.0).abs() < 1e-9, "material is moved, not lost: {total}");
+        for y in 0..9 {
+            for x in 0..9 {
+                for (dx, dy) in [(1, 0), (0, 1)] {
+                    let slope = (map.get_value(x, y) - map.get_value(x + dx, y + dy)).abs();
+                    assert!(slope <= 1.0 + 0.05, "slope {slope} at ({x}, {y}) is steeper than the talus angle");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn thermal_erosion_honours_edge_mode() {
+        let spike_at_border = |edge_mode: EdgeMode| {
+            let mut map = ElevationMap::new(5, 5).with_edge_mode(edge_mode);
+            map._set_value(0, 2, 4.0);
+            erode_thermal(&mut map, &ThermalErosion { iterations: 1, ..Default::default() });
+            map
+        };
+        // wrapped maps move material across the border, to the right side of the map
+        let wrapped = spike_at_border(EdgeMode::Wrap);
+        assert!(wrapped.get_value(4, 2) > 0.0);
+        assert!((wrapped.values().iter().sum::<f64>() - 4.0).abs() < 1e-9);
+        // the map ends at the sea level, material beyond the border is lost
+        let sea = spike_at_border(EdgeMode::SeaLevel(0.0));
+        assert_eq!(sea.get_value(4, 2), 0.0);
+        assert!(sea.values().iter().sum::<f64>() < 4.0);
+    }
+}
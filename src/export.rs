@@ -0,0 +1,215 @@
+use std::io::{self, Write};
+use std::path::Path;
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, VertexAttributeValues};
+use serde_json::json;
+use crate::mesh::ElevationMap;
+
+/// Saves the elevation map as 16-bit grayscale PNG, where `max_height` is the brightest value.
+/// This is the inverse of loading it with the same `max_height`.
+pub fn save_elevation_map_png16(map: &ElevationMap, path: impl AsRef<Path>, max_height: f64) -> io::Result<()> {
+    let (width, height) = map.size();
+    let values = map.values().iter().map(|value| (value / max_height * 65536.0).clamp(0.0, 65535.0) as u16).collect();
+    let image = image::ImageBuffer::<image::Luma<u16>, Vec<u16>>::from_raw(width as u32, height as u32, values)
+        .expect("the elevation map has width * height values");
+    image.save_with_format(path, image::ImageFormat::Png).map_err(io::Error::other)
+}
+
+/// Saves the elevation map as headerless little-endian 32-bit floats, where `max_height` is 1.0.
+/// This is the inverse of loading it as `HeightmapFormat::RawR32` with the same `max_height`.
+pub fn save_elevation_map_r32(map: &ElevationMap, path: impl AsRef<Path>, max_height: f64) -> io::Result<()> {
+    let bytes: Vec<u8> = map.values().iter().flat_map(|value| ((value / max_height) as f32).to_le_bytes()).collect();
+    std::fs::write(path, bytes)
+}
+
+/// Vertex data of a triangle mesh, as needed by the exporters.
+struct MeshData<'a> {
+    positions: &'a [[f32; 3]],
+    normals: &'a [[f32; 3]],
+    uvs: &'a [[f32; 2]],
+    indices: Vec<u32>,
+}
+
+impl<'a> MeshData<'a> {
+    fn new(mesh: &'a Mesh) -> io::Result<Self> {
+        let missing = |name: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("mesh has no {name}"));
+        let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions,
+            _ => return Err(missing("positions")),
+        };
+        let normals = match mesh.attribute(Mesh::ATTRIBUTE_NORMAL) {
+            Some(VertexAttributeValues::Float32x3(normals)) => normals,
+            _ => return Err(missing("normals")),
+        };
+        let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+            Some(VertexAttributeValues::Float32x2(uvs)) => uvs,
+            _ => return Err(missing("uvs")),
+        };
+        let indices = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices.clone(),
+            Some(Indices::U16(indices)) => indices.iter().map(|&i| i as u32).collect(),
+            None => (0..positions.len() as u32).collect(),
+        };
+        Ok(Self { positions, normals, uvs, indices })
+    }
+}
+
+/// Writes the named meshes as objects of a Wavefront OBJ file, with positions, normals and UVs.
+/// The meshes are expected to be triangle lists in world space, like the terrain chunks.
+pub fn write_obj(meshes: &[(String, &Mesh)], writer: impl Write) -> io::Result<()> {
+    let mut writer = io::BufWriter::new(writer);
+    writeln!(writer, "# terrain exported by rust-bevy-fun")?;
+    let mut offset = 1;
+    for (name, mesh) in meshes {
+        let data = MeshData::new(mesh)?;
+        writeln!(writer, "o {name}")?;
+        for [x, y, z] in data.positions {
+            writeln!(writer, "v {x} {y} {z}")?;
+        }
+        for [x, y, z] in data.normals {
+            writeln!(writer, "vn {x} {y} {z}")?;
+        }
+        // OBJ texture coordinates start at the bottom
+        for [u, v] in data.uvs {
+            writeln!(writer, "vt {u} {}", 1.0 - v)?;
+        }
+        for triangle in data.indices.chunks_exact(3) {
+            let [a, b, c] = [triangle[0] + offset, triangle[1] + offset, triangle[2] + offset];
+            writeln!(writer, "f {a}/{a}/{a} {b}/{b}/{b} {c}/{c}/{c}")?;
+        }
+        offset += data.positions.len() as u32;
+    }
+    writer.flush()
+}
+
+/// Writes the named meshes as nodes of a binary glTF (GLB) file, with positions, normals and UVs.
+/// The meshes are expected to be triangle lists in world space, like the terrain chunks.
+pub fn write_glb(meshes: &[(String, &Mesh)], mut writer: impl Write) -> io::Result<()> {
+    const ARRAY_BUFFER: u32 = 34962;
+    const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+    const FLOAT: u32 = 5126;
+    const UNSIGNED_INT: u32 = 5125;
+
+    let mut buffer: Vec<u8> = Vec::new();
+    let (mut buffer_views, mut accessors, mut gltf_meshes, mut nodes) = (Vec::new(), Vec::new(), Vec::new(), Vec::new());
+    // adds a buffer view with an accessor and returns the index of the accessor
+    let mut add_accessor = |bytes: &[u8], target: u32, component_type: u32, count: usize, kind: &str, bounds: Option<([f32; 3], [f32; 3])>| {
+        let view = json!({ "buffer": 0, "byteOffset": buffer.len(), "byteLength": bytes.len(), "target": target });
+        buffer.extend_from_slice(bytes);
+        buffer.resize(buffer.len().next_multiple_of(4), 0);
+        buffer_views.push(view);
+        let mut accessor = json!({ "bufferView": buffer_views.len() - 1, "componentType": component_type, "count": count, "type": kind });
+        if let Some((min, max)) = bounds {
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+        accessors.push(accessor);
+        accessors.len() - 1
+    };
+
+    for (name, mesh) in meshes {
+        let data = MeshData::new(mesh)?;
+        let bounds = data.positions.iter().fold(([f32::MAX; 3], [f32::MIN; 3]), |(min, max), position| {
+            (std::array::from_fn(|i| min[i].min(position[i])), std::array::from_fn(|i| max[i].max(position[i])))
+        });
+        let count = data.positions.len();
+        let floats = |values: &mut dyn Iterator<Item = f32>| values.flat_map(f32::to_le_bytes).collect::<Vec<u8>>();
+        let position = add_accessor(&floats(&mut data.positions.iter().flatten().copied()), ARRAY_BUFFER, FLOAT, count, "VEC3", Some(bounds));
+        let normal = add_accessor(&floats(&mut data.normals.iter().flatten().copied()), ARRAY_BUFFER, FLOAT, count, "VEC3", None);
+        let uv = add_accessor(&floats(&mut data.uvs.iter().flatten().copied()), ARRAY_BUFFER, FLOAT, count, "VEC2", None);
+        let index_bytes: Vec<u8> = data.indices.iter().flat_map(|index| index.to_le_bytes()).collect();
+        let indices = add_accessor(&index_bytes, ELEMENT_ARRAY_BUFFER, UNSIGNED_INT, data.indices.len(), "SCALAR", None);
+        gltf_meshes.push(json!({
+            "name": name,
+            "primitives": [{ "attributes": { "POSITION": position, "NORMAL": normal, "TEXCOORD_0": uv }, "indices": indices }],
+        }));
+        nodes.push(json!({ "name": name, "mesh": gltf_meshes.len() - 1 }));
+    }
+
+    let document = json!({
+        "asset": { "version": "2.0", "generator": "rust-bevy-fun" },
+        "scene": 0,
+        "scenes": [{ "nodes": (0..nodes.len()).collect::<Vec<usize>>() }],
+        "nodes": nodes,
+        "meshes": gltf_meshes,
+        "accessors": accessors,
+        "bufferViews": buffer_views,
+        "buffers": [{ "byteLength": buffer.len() }],
+    });
+    let mut document = serde_json::to_vec(&document)?;
+    // chunks are 4 byte aligned, the JSON chunk is padded with spaces
+    document.resize(document.len().next_multiple_of(4), b' ');
+
+    let length = 12 + 8 + document.len() + 8 + buffer.len();
+    writer.write_all(b"glTF")?;
+    writer.write_all(&2u32.to_le_bytes())?;
+    writer.write_all(&(length as u32).to_le_bytes())?;
+    writer.write_all(&(document.len() as u32).to_le_bytes())?;
+    writer.write_all(b"JSON")?;
+    writer.write_all(&document)?;
+    writer.write_all(&(buffer.len() as u32).to_le_bytes())?;
+    writer.write_all(b"BIN\0")?;
+    writer.write_all(&buffer)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::mesh::{create_mesh, decode_elevation_map, HeightmapFormat, Lod};
+    use crate::source::ImageSource;
+
+    fn test_map() -> ElevationMap {
+        ElevationMap::new_with_data(4, 3, (0..12).map(|i| i as f64 * 0.25).collect())
+    }
+
+    #[test]
+    fn elevation_map_round_trips() {
+        let dir = std::env::temp_dir().join(format!("terrain-export-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let map = test_map();
+        for (file, format) in [("map.png", HeightmapFormat::Image), ("map.r32", HeightmapFormat::RawR32)] {
+            let path = dir.join(file);
+            match format {
+                HeightmapFormat::Image => save_elevation_map_png16(&map, &path, 4.0).unwrap(),
+                _ => save_elevation_map_r32(&map, &path, 4.0).unwrap(),
+            }
+            let loaded = decode_elevation_map(&std::fs::read(&path).unwrap(), format, Some((4, 3)), 4.0).unwrap();
+            assert_eq!(loaded.size(), (4, 3));
+            for (loaded, original) in loaded.values().iter().zip(map.values()) {
+                assert!((loaded - original).abs() < 1e-4, "{file}: {loaded} != {original}");
+            }
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn meshes_are_written_as_obj_and_glb() {
+        let source = ImageSource::new(Arc::new(test_map()), (1.0, 1.0));
+        let mesh = create_mesh(4.0, (0, 0), (4, 3), &source, 1.0, Lod { stride: 1, skirt_depth: 1.0 }, (4.0, 4.0));
+        let vertices = mesh.count_vertices();
+        let meshes = [("a".to_string(), &mesh), ("b".to_string(), &mesh)];
+
+        let mut obj = Vec::new();
+        write_obj(&meshes, &mut obj).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        assert_eq!(obj.lines().filter(|line| line.starts_with("v ")).count(), vertices * 2);
+        assert_eq!(obj.lines().filter(|line| line.starts_with("o ")).count(), 2);
+        // indices of the second object continue after the vertices of the first one
+        let max_index = obj.lines().filter(|line| line.starts_with("f ")).flat_map(|line| line.split([' ', '/']).skip(1))
+            .map(|index| index.parse::<usize>().unwrap()).max().unwrap();
+        assert_eq!(max_index, vertices * 2);
+
+        let mut glb = Vec::new();
+        write_glb(&meshes, &mut glb).unwrap();
+        assert_eq!(&glb[0..4], b"glTF");
+        assert_eq!(u32::from_le_bytes(glb[8..12].try_into().unwrap()) as usize, glb.len());
+        let json_length = u32::from_le_bytes(glb[12..16].try_into().unwrap()) as usize;
+        let document: serde_json::Value = serde_json::from_slice(&glb[20..20 + json_length]).unwrap();
+        assert_eq!(document["nodes"].as_array().unwrap().len(), 2);
+        assert_eq!(document["accessors"][0]["count"], vertices);
+        let buffer_length = document["buffers"][0]["byteLength"].as_u64().unwrap() as usize;
+        assert_eq!(glb.len(), 20 + json_length + 8 + buffer_length);
+    }
+}
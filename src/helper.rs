@@ -1,5 +1,8 @@
 #![allow(unused)]
+use std::collections::VecDeque;
 use bevy::prelude::Vec3;
+use rust_bevy_fun::chunk::ChunkCoord;
+use serde::{Deserialize, Serialize};
 
 #[inline(always)]
 pub fn format_vec3f(vec: Vec3) -> String {
@@ -11,7 +14,7 @@ pub fn println_vec3f(vec: Vec3) {
     println!("({:>8.3},{:>8.3},{:>8.3})", vec[0], vec[1], vec[2]);
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SimpleTween {
     cur:f32,
     min:f32,
@@ -36,7 +39,7 @@ impl SimpleTween {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct VelocityTween {
     cur:Vec3,
     min:f32,
@@ -54,7 +57,7 @@ impl VelocityTween {
     /// Adds the given direction vector * delta_seconds * increase_step_length to the current velocity vector,
     /// but also respoects the minimum and maximum velocity in any direction.
     pub fn add_velocity(&mut self, delta_seconds: f32, direction:Vec3) {
-        let new_velocity = self.cur + (delta_seconds as f32 * self.inc * direction.normalize());
+        let new_velocity = self.cur + (delta_seconds * self.inc * direction.normalize());
         let new_speed = new_velocity.length();
         let valid_speed = new_speed.max(self.min).min(self.max);
         let valid_velocity = match new_speed {
@@ -70,3 +73,145 @@ impl VelocityTween {
     }
 }
 
+
+/// A minimal least-recently-used cache with a fixed capacity.
+/// Entries are kept in usage order, so lookups are linear, which is fine for small capacities.
+#[derive(Debug)]
+pub struct LruCache<K, V> {
+    capacity: usize,
+    entries: VecDeque<(K, V)>,
+}
+impl<K: PartialEq, V> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: VecDeque::with_capacity(capacity) }
+    }
+    /// Inserts the given value as the most recently used one.
+    /// Returns the evicted entries (an older value of the same key and any least recently used ones),
+    /// so the caller can release them.
+    pub fn put(&mut self, key: K, value: V) -> Vec<(K, V)> {
+        let mut evicted = Vec::new();
+        if let Some(index) = self.entries.iter().position(|(k, _)| *k == key) {
+            evicted.extend(self.entries.remove(index));
+        }
+        self.entries.push_front((key, value));
+        while self.entries.len() > self.capacity {
+            evicted.extend(self.entries.pop_back());
+        }
+        evicted
+    }
+    /// Removes and returns the value of the given key, if cached.
+    pub fn take(&mut self, key: &K) -> Option<V> {
+        let index = self.entries.iter().position(|(k, _)| k == key)?;
+        self.entries.remove(index).map(|(_, v)| v)
+    }
+    /// Returns the keys of all entries, the most recently used first.
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.entries.iter().map(|(k, _)| k)
+    }
+    /// Removes and returns all entries.
+    pub fn drain(&mut self) -> impl Iterator<Item = (K, V)> + '_ {
+        self.entries.drain(..)
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// Returns the loaded chunks beyond the unload radius around the player.
+/// Chunks between the load and the unload radius stay loaded (hysteresis),
+/// so moving back and forth across a chunk border doesn't reload chunks.
+pub fn chunks_to_unload<'a>(loaded: impl IntoIterator<Item = &'a ChunkCoord>, player: ChunkCoord, load_radius: i32, unload_radius: i32) -> Vec<ChunkCoord> {
+    let unload_radius = unload_radius.max(load_radius);
+    loaded.into_iter()
+        .filter(|chunk| chunk.distance(player) > unload_radius)
+        .copied()
+        .collect()
+}
+
+/// Returns the value following the command line argument `name` (e.g. `--seed 42`)
+pub fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
+/// Returns the parsed value following the given command line argument,
+/// an invalid value is reported and ignored
+pub fn arg_parse<T: std::str::FromStr>(name: &str) -> Option<T> where T::Err: std::fmt::Display {
+    let value = arg_value(name)?;
+    match value.parse() {
+        Ok(value) => Some(value),
+        Err(err) => {
+            eprintln!("Invalid value {value} for {name} ({err}), using the default");
+            None
+        },
+    }
+}
+
+/// Like `arg_parse`, but values which aren't positive and finite are reported and ignored as well
+pub fn arg_parse_positive(name: &str) -> Option<f64> {
+    let value: f64 = arg_parse(name)?;
+    if value > 0.0 && value.is_finite() {
+        Some(value)
+    } else {
+        eprintln!("Invalid value {value} for {name} (must be positive), using the default");
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let mut cache = LruCache::new(2);
+        assert!(cache.put(1, "a").is_empty());
+        assert!(cache.put(2, "b").is_empty());
+        // taking and putting back makes an entry the most recently used one
+        let a = cache.take(&1).unwrap();
+        assert!(cache.put(1, a).is_empty());
+        assert_eq!(cache.put(3, "c"), vec![(2, "b")]);
+        assert_eq!(cache.keys().copied().collect::<Vec<_>>(), vec![3, 1]);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn replaced_values_are_returned() {
+        let mut cache = LruCache::new(2);
+        cache.put(1, "a");
+        cache.put(2, "b");
+        assert_eq!(cache.put(1, "new a"), vec![(1, "a")]);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.take(&1), Some("new a"));
+        assert_eq!(cache.take(&1), None);
+        // without capacity, every entry is evicted right away
+        let mut cache = LruCache::new(0);
+        assert_eq!(cache.put(1, "a"), vec![(1, "a")]);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn drain_empties_the_cache() {
+        let mut cache = LruCache::new(3);
+        for (key, value) in [(1, "a"), (2, "b"), (3, "c")] {
+            cache.put(key, value);
+        }
+        assert_eq!(cache.drain().collect::<Vec<_>>(), vec![(3, "c"), (2, "b"), (1, "a")]);
+        assert!(cache.is_empty());
+        assert_eq!(cache.take(&1), None);
+    }
+
+    #[test]
+    fn chunks_between_the_radii_stay_loaded() {
+        let loaded: Vec<ChunkCoord> = (-4..=4).map(|x| ChunkCoord(x, 1)).collect();
+        let player = ChunkCoord(0, 0);
+        assert_eq!(chunks_to_unload(&loaded, player, 2, 3), vec![ChunkCoord(-4, 1), ChunkCoord(4, 1)]);
+        assert!(chunks_to_unload(&loaded, player, 2, 4).is_empty());
+        // the unload radius is at least the load radius
+        assert_eq!(chunks_to_unload(&loaded, player, 3, 1), vec![ChunkCoord(-4, 1), ChunkCoord(4, 1)]);
+    }
+}
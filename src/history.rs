@@ -0,0 +1,205 @@
+use std::collections::{HashMap, VecDeque};
+use crate::mesh::ElevationMap;
+
+/// Changed texels of one edit, e.g. a brush stroke: the indices (x + y * width) with their old and new values.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MapEdit {
+    indices: Vec<u32>,
+    old_values: Vec<f64>,
+    new_values: Vec<f64>,
+}
+
+impl MapEdit {
+    const BYTES_PER_TEXEL: usize = std::mem::size_of::<u32>() + 2 * std::mem::size_of::<f64>();
+
+    /// Returns the indices (x + y * width) of the changed texels.
+    pub fn indices(&self) -> impl Iterator<Item = usize> + '_ {
+        self.indices.iter().map(|&index| index as usize)
+    }
+
+    /// Returns the approximate memory used by the edit.
+    pub fn memory(&self) -> usize {
+        std::mem::size_of::<Self>() + self.indices.len() * Self::BYTES_PER_TEXEL
+    }
+
+    fn write(map: &mut ElevationMap, indices: &[u32], values: &[f64]) {
+        let map_values = map.values_mut();
+        for (&index, &value) in indices.iter().zip(values) {
+            map_values[index as usize] = value;
+        }
+    }
+}
+
+/// Undo and redo history of elevation map edits within a memory budget (in bytes).
+/// Changes are collected in an open edit (e.g. while a brush stroke goes on), which is pushed by `commit`.
+/// When the budget is exceeded, the oldest edits are forgotten.
+pub struct EditHistory {
+    undo: VecDeque<MapEdit>,
+    redo: Vec<MapEdit>,
+    /// old values of the texels changed by the open edit
+    open: HashMap<u32, f64>,
+    budget: usize,
+    memory: usize,
+}
+
+impl EditHistory {
+    pub fn new(budget: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            open: HashMap::new(),
+            budget,
+            memory: 0,
+        }
+    }
+
+    /// Records changed texels of the open edit, as returned by `Brush::apply`: their index and previous value.
+    pub fn record(&mut self, changes: &[(usize, f64)]) {
+        for &(index, old_value) in changes {
+            self.open.entry(index as u32).or_insert(old_value);
+        }
+    }
+
+    /// Sets an elevation value of the map and records the change in the open edit.
+    pub fn set_value(&mut self, map: &mut ElevationMap, x: usize, y: usize, value: f64) {
+        let (width, height) = map.size();
+        if x < width && y < height {
+            let index = x + y * width;
+            self.record(&[(index, map.values()[index])]);
+            map.values_mut()[index] = value;
+        }
+    }
+
+    /// Pushes the open edit onto the undo stack, taking the new values from the map.
+    /// Texels which got back their old value are left out, the redo stack is cleared by new edits.
+    pub fn commit(&mut self, map: &ElevationMap) {
+        let mut changes: Vec<(u32, f64)> = self.open.drain().collect();
+        changes.sort_unstable_by_key(|(index, _)| *index);
+        let mut edit = MapEdit::default();
+        for (index, old_value) in changes {
+            let new_value = map.values()[index as usize];
+            if new_value != old_value {
+                edit.indices.push(index);
+                edit.old_values.push(old_value);
+                edit.new_values.push(new_value);
+            }
+        }
+        if edit.indices.is_empty() {
+            return;
+        }
+        self.memory -= self.redo.drain(..).map(|edit| edit.memory()).sum::<usize>();
+        self.memory += edit.memory();
+        self.undo.push_back(edit);
+        while self.memory > self.budget {
+            let Some(oldest) = self.undo.pop_front() else { break };
+            self.memory -= oldest.memory();
+        }
+    }
+
+    /// Reverts the last edit (committing the open one first) and returns it, to rebuild the changed terrain.
+    pub fn undo(&mut self, map: &mut ElevationMap) -> Option<&MapEdit> {
+        self.commit(map);
+        let edit = self.undo.pop_back()?;
+        MapEdit::write(map, &edit.indices, &edit.old_values);
+        self.redo.push(edit);
+        self.redo.last()
+    }
+
+    /// Applies the last undone edit again and returns it, to rebuild the changed terrain.
+    pub fn redo(&mut self, map: &mut ElevationMap) -> Option<&MapEdit> {
+        self.commit(map);
+        let edit = self.redo.pop()?;
+        MapEdit::write(map, &edit.indices, &edit.new_values);
+        self.undo.push_back(edit);
+        self.undo.back()
+    }
+
+    /// Forgets all edits, e.g. when the map was replaced.
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.open.clear();
+        self.memory = 0;
+    }
+
+    /// Returns the number of edits which can be undone and redone.
+    pub fn counts(&self) -> (usize, usize) {
+        (self.undo.len(), self.redo.len())
+    }
+
+    /// Returns the memory used by the recorded edits.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::brush::Brush;
+
+    fn map() -> ElevationMap {
+        ElevationMap::new_with_data(16, 16, (0..256).map(|i| i as f64 * 0.01).collect())
+    }
+
+    #[test]
+    fn undo_and_redo_restore_the_map() {
+        let original = map();
+        let mut map = original.clone();
+        let mut history = EditHistory::new(1 << 20);
+        let brush = Brush { radius: 3.0, ..Default::default() };
+        // a stroke over several frames is a single edit
+        for frame in 0..3 {
+            let changes = brush.apply(&mut map, (5.0 + frame as f64, 5.0), 0.1);
+            history.record(&changes);
+        }
+        history.commit(&map);
+        history.set_value(&mut map, 15, 15, 9.0);
+        history.commit(&map);
+        let edited = map.clone();
+        assert_eq!(history.counts(), (2, 0));
+
+        let edit = history.undo(&mut map).unwrap();
+        assert_eq!(edit.indices().collect::<Vec<_>>(), vec![255]);
+        let edit = history.undo(&mut map).unwrap();
+        assert!(edit.indices().all(|index| (index % 16) < 11 && (index / 16) < 9));
+        assert_eq!(map.values(), original.values());
+        assert!(history.undo(&mut map).is_none());
+
+        history.redo(&mut map);
+        history.redo(&mut map);
+        assert_eq!(map.values(), edited.values());
+        assert_eq!(history.counts(), (2, 0));
+    }
+
+    #[test]
+    fn new_edits_clear_redo_and_budget_drops_oldest_edits() {
+        let mut map = map();
+        let mut history = EditHistory::new(1 << 20);
+        history.set_value(&mut map, 0, 0, 1.0);
+        history.commit(&map);
+        history.undo(&mut map);
+        history.set_value(&mut map, 1, 0, 1.0);
+        history.commit(&map);
+        assert_eq!(history.counts(), (1, 0));
+
+        // unchanged texels aren't recorded
+        let unchanged = map.values()[2];
+        history.set_value(&mut map, 2, 0, unchanged);
+        history.commit(&map);
+        assert_eq!(history.counts(), (1, 0));
+
+        let edit_memory = history.memory();
+        let mut history = EditHistory::new(edit_memory * 3);
+        for x in 0..5 {
+            history.set_value(&mut map, x, 1, 5.0);
+            history.commit(&map);
+        }
+        assert_eq!(history.counts(), (3, 0));
+        assert!(history.memory() <= edit_memory * 3);
+        // the oldest edits are gone, their changes stay
+        while history.undo(&mut map).is_some() {}
+        assert_eq!(map.get_value(1, 1), 5.0);
+        assert_eq!(map.get_value(2, 1), 0.18);
+    }
+}
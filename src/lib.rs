@@ -0,0 +1,12 @@
+//! Terrain generation, shared by the game and the `terrain-tool` binary.
+pub mod brush;
+pub mod chunk;
+pub mod delta;
+pub mod erosion;
+pub mod export;
+pub mod history;
+pub mod mesh;
+pub mod ops;
+pub mod raycast;
+pub mod source;
+pub mod tiled;
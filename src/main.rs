@@ -1,11 +1,20 @@
 use std::f32::consts::TAU;
+use std::sync::Arc;
+use bevy::asset::LoadState;
+use bevy::ecs::system::SystemParam;
 use bevy::input::common_conditions::input_toggle_active;
 use bevy::input::mouse::{MouseMotion, MouseButton};
+use bevy::tasks::{block_on, AsyncComputeTaskPool, Task};
 use bevy::utils::HashMap;
 //use bevy::pbr::wireframe::{Wireframe, WireframePlugin};
 use bevy_rapier3d::prelude::{RapierPhysicsPlugin, NoUserData};
+use cursor::CursorPlugin;
 use debug::DebugTextPlugin;
-use mesh::{create_mesh, load_elevation_map, ElevationMap};
+use save::SavePlugin;
+use sculpt::SculptPlugin;
+use rust_bevy_fun::chunk::{ChunkCoord, ChunkLayout};
+use rust_bevy_fun::mesh::{create_chunk, generate_noisemap, noisemap_to_elevation_map, surface_height, surface_normal, ElevationMap, ElevationMapLoader, Heightmap, Lod};
+use rust_bevy_fun::source::{ImageSource, NoiseSource, PlaneSource, TerrainSource, WorldSource};
 use rand::prelude::*;
 use bevy::prelude::*;
 use bevy::diagnostic::LogDiagnosticsPlugin;
@@ -14,13 +23,20 @@ use bevy_rapier3d::prelude::*;
 //use bevy::window::{CursorGrabMode, Cursor};
 //use bevy_rapier3d::render::RapierDebugRenderPlugin;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
-use helper::{ SimpleTween, VelocityTween };
+use rust_bevy_fun::history::EditHistory;
+use rust_bevy_fun::tiled::TiledElevationMap;
+use rust_bevy_fun::export::{save_elevation_map_png16, save_elevation_map_r32, write_glb, write_obj};
+use rust_bevy_fun::erosion::{erode_hydraulic, erode_thermal, HydraulicErosion, ThermalErosion};
+use helper::{ arg_parse, arg_parse_positive, arg_value, chunks_to_unload, LruCache, SimpleTween, VelocityTween };
 
 mod helper;
-mod mesh;
+mod cursor;
 mod debug;
+mod save;
+mod sculpt;
 
 fn main() {
+    let elevation_map_loader = ElevationMapLoader::default();
     App::new()
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -37,17 +53,34 @@ fn main() {
         }))
         .add_systems(Update, bevy::window::close_on_esc)
         .add_plugins(LogDiagnosticsPlugin::default())
-        .add_plugins(FrameTimeDiagnosticsPlugin::default())
+        .add_plugins(FrameTimeDiagnosticsPlugin)
         .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
         //.add_plugin(RapierDebugRenderPlugin::default())
         //.add_plugin(WireframePlugin)
         .add_systems(Startup, setup)
         .add_plugins(DebugTextPlugin)
+        .add_plugins(CursorPlugin)
+        .add_plugins(SculptPlugin)
+        .add_plugins(SavePlugin)
         .add_plugins(WorldInspectorPlugin::default().run_if(input_toggle_active(false, KeyCode::I)))
-        .insert_resource(Terrain::default())
+        .init_asset::<ElevationMap>()
+        .register_asset_loader(elevation_map_loader.clone())
+        .insert_resource(elevation_map_loader)
+        .insert_resource(WorldSeed::from_args_or_env())
+        .insert_resource(Terrain {
+            kind: TerrainKind::from_args(),
+            hydraulic_erosion: hydraulic_erosion_from_args(),
+            thermal_erosion: thermal_erosion_from_args(),
+            chunk_size: arg_parse_positive("--chunk-size").unwrap_or(Terrain::DEFAULT_CHUNK_SIZE),
+            chunk_resolution: Terrain::lod_resolution(arg_parse("--chunk-resolution").unwrap_or(Terrain::DEFAULT_CHUNK_RESOLUTION)),
+            texels_per_unit: arg_parse_positive("--texels-per-unit").unwrap_or(Terrain::DEFAULT_TEXELS_PER_UNIT),
+            ..default()
+        })
         .add_systems(Update, user_actions)
-        .add_systems(Update, map_update)
+        // chunks are updated before map_update, which may despawn them
+        .add_systems(Update, (elevation_map_update, chunk_lod_update, finalize_chunks, map_update).chain())
         .add_systems(Update, cube_orbit_movement)
+        .add_systems(Update, export_terrain)
         .run();
 }
 
@@ -55,6 +88,12 @@ fn main() {
 #[derive(Component)]
 struct MovableCube;
 
+/// Query filter for the ball, disjoint from the cube and the camera
+type BallFilter = (With<MovableBall>, Without<MovableCube>, Without<CameraControl>);
+
+/// Query data of the pending chunks, with the mesh of rebuilt chunks
+type PendingChunkData<'a> = (Entity, &'a mut PendingChunk, Option<&'a Handle<Mesh>>, &'a TerrainMesh, Has<DirtyChunk>);
+
 #[derive(Component,Debug)]
 struct MovableBall {
     velocity:VelocityTween,
@@ -63,7 +102,12 @@ struct MovableBall {
 impl MovableBall {
     const RADIUS:f32 = 0.5;
     const INITIAL_POSITION:Transform = Transform::from_xyz(100.0, 20.0, 100.0);
+    /// the ball respawns when it falls this far below the ground (e.g. through a hole in the terrain)
     const DEATH_HEIGHT:f32 = -10.0;
+    /// distance to the ground which still counts as touching it
+    const GROUND_TOLERANCE:f32 = 0.1;
+    /// minimum height above the ground when respawning
+    const SPAWN_CLEARANCE:f32 = 2.0;
     const MAX_MOVEMENT_SPEED:f32 = 24.0;
     const INC_MOVEMENT_SPEED:f32 = 20.0; // times delta_seconds
     const SHIFT_MOVEMENT_MULTIPLIER:f32 = 3.0;
@@ -94,50 +138,365 @@ impl Default for MovableBall {
 
 #[derive(Resource)]
 struct Terrain {
-    size: f64,
+    /// size (x and z) of a chunk in world units, set with `--chunk-size <units>`
+    chunk_size: f64,
     intensity: f32,
+    kind: TerrainKind,
     colormap: Option<Handle<Image>>,
-    map: Option<ElevationMap>,
-    mesh_size: (usize, usize), 
-    entity_map: HashMap<(usize,usize),Entity>
+    /// the elevation map asset, reloaded when its file changes
+    elevation_map: Handle<ElevationMap>,
+    /// the elevation map used for creating chunks, taken from the asset (or generated if it can't be loaded)
+    map: Option<Arc<ElevationMap>>,
+    /// the elevation map before any edits, save files only store the changes to it
+    base_map: Option<Arc<ElevationMap>>,
+    /// the elevation map of the tiled terrain, whose tiles are paged in while creating chunks (and can't be edited)
+    tiled_map: Option<Arc<TiledElevationMap>>,
+    /// the source of the elevation values of all chunks, chunks aren't created before it is set
+    source: Option<Arc<dyn TerrainSource>>,
+    /// number of mesh cells along each side of a chunk at full level of detail, set with `--chunk-resolution <cells>`
+    chunk_resolution: usize,
+    /// density of the elevation map, set with `--texels-per-unit <texels>`
+    texels_per_unit: f64,
+    entity_map: HashMap<ChunkCoord,Entity>,
+    /// chunks within this distance (in chunks) around the player are created
+    load_radius: i32,
+    /// chunks beyond this distance (in chunks) are unloaded, must be >= load_radius (hysteresis)
+    unload_radius: i32,
+    /// recently unloaded chunk meshes and colliders, to avoid regenerating them when coming back
+    chunk_cache: Option<LruCache<ChunkCoord,CachedChunk>>,
+    /// maximum number of generated chunks inserted into the world per frame
+    max_chunks_per_frame: usize,
+    /// level of detail bands, ordered by distance; chunks beyond the last band use its stride
+    lod_bands: Vec<LodBand>,
+    /// depth of the skirts hiding cracks between chunks of different level of detail
+    skirt_depth: f32,
+    /// hydraulic erosion applied to loaded elevation maps, enabled with `--erode <droplets>`
+    hydraulic_erosion: Option<HydraulicErosion>,
+    /// thermal erosion applied to loaded elevation maps (before the hydraulic erosion),
+    /// enabled with `--thermal <iterations>`
+    thermal_erosion: Option<ThermalErosion>,
+    /// undo and redo history of the edits of the elevation map
+    history: EditHistory,
 }
 impl Terrain {
-    const DEFAULT_SIZE:f64 = 200.0;
+    const DEFAULT_CHUNK_SIZE:f64 = 50.0;
+    const DEFAULT_CHUNK_RESOLUTION:usize = 256;
+    // the 1024 * 768 elevation map covers 200 * 150 world units
+    const DEFAULT_TEXELS_PER_UNIT:f64 = 5.12;
     const DEFAULT_INTENSITY:f32 = 4.0;
+    const DEFAULT_LOAD_RADIUS:i32 = 6;
+    const DEFAULT_UNLOAD_RADIUS:i32 = 7;
+    const DEFAULT_CHUNK_CACHE_SIZE:usize = 32;
+    const DEFAULT_MAX_CHUNKS_PER_FRAME:usize = 4;
+    const DEFAULT_LOD_BANDS:[LodBand; 4] = [
+        LodBand { max_distance: 50.0, stride: 1 },
+        LodBand { max_distance: 200.0, stride: 4 },
+        LodBand { max_distance: 400.0, stride: 8 },
+        LodBand { max_distance: f32::INFINITY, stride: 16 },
+    ];
+    const DEFAULT_SKIRT_DEPTH:f32 = 4.0;
+    const DEFAULT_HISTORY_BUDGET:usize = 64 * 1024 * 1024;
+    /// memory budget (in MB) of the loaded tiles of a tiled elevation map, set with `--tile-budget <MB>`
+    const DEFAULT_TILE_BUDGET_MB:usize = 256;
+    const EXPORT_DIRECTORY:&'static str = "export";
+    /// generated noise repeats its features (one noise unit) every this many world units
+    const NOISE_FEATURE_SIZE:f64 = 200.0;
+    const GENERATED_MAX_HEIGHT:f64 = 4.0;
+    const WORLD_FEATURE_SIZE:f64 = 400.0;
+    const WORLD_MAX_HEIGHT:f64 = 16.0;
+    /// Rounds the chunk resolution up to a multiple of the largest level of detail stride,
+    /// so the vertices are evenly spaced at every level of detail, like the heightfield colliders expect
+    fn lod_resolution(resolution: usize) -> usize {
+        let max_stride = Terrain::DEFAULT_LOD_BANDS.iter().map(|band| band.stride).max().unwrap_or(1);
+        let rounded = resolution.max(1).next_multiple_of(max_stride);
+        if rounded != resolution {
+            eprintln!("Chunk resolution {resolution} isn't a multiple of the level of detail stride {max_stride}, using {rounded}");
+        }
+        rounded
+    }
     fn _reset(&mut self) {
-        self.size = Terrain::DEFAULT_SIZE;
+        self.chunk_size = Terrain::DEFAULT_CHUNK_SIZE;
         self.intensity = Terrain::DEFAULT_INTENSITY;
     }
-    fn get_map(&self) -> &ElevationMap {
-        self.map.as_ref().unwrap()
+    fn get_source(&self) -> Arc<dyn TerrainSource> {
+        self.source.as_ref().unwrap().clone()
+    }
+    /// Returns the elevation map of the terrain, either in memory or paged in from a tiled file
+    fn heightmap(&self) -> Option<&dyn Heightmap> {
+        self.map.as_deref().map(|map| map as &dyn Heightmap)
+            .or_else(|| self.tiled_map.as_deref().map(|map| map as &dyn Heightmap))
+    }
+    /// Returns the material of the chunks, textured with the colormap if there is one
+    fn get_material(&self) -> StandardMaterial {
+        match &self.colormap {
+            Some(colormap) => colormap.clone().into(),
+            None => Color::rgb(0.3, 0.5, 0.3).into(),
+        }
+    }
+    /// Returns the layout of the chunks, converting between world positions, chunks and texels.
+    /// Chunk coordinates aren't wrapped, the terrain continues in every direction.
+    fn layout(&self) -> ChunkLayout {
+        ChunkLayout::new(self.chunk_size, self.chunk_resolution, self.texels_per_unit)
+    }
+    /// Returns the height of the ground at the world position (x, z), as shown by the chunk meshes at full detail.
+    /// Returns None while the terrain source isn't set.
+    fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let source = self.source.as_ref()?;
+        Some(surface_height(source.as_ref(), x as f64, z as f64, self.intensity))
+    }
+    /// Returns the normal of the ground at the world position (x, z), see `height_at`
+    fn normal_at(&self, x: f32, z: f32) -> Option<Vec3> {
+        let source = self.source.as_ref()?;
+        let cell_size = self.layout().cell_size();
+        Some(surface_normal(source.as_ref(), x as f64, z as f64, (cell_size, cell_size), self.intensity))
+    }
+    /// Returns the level of detail for a chunk, based on its distance to the camera
+    fn lod_for_distance(&self, distance: f32) -> Lod {
+        let stride = self.lod_bands.iter()
+            .find(|band| distance < band.max_distance)
+            .or(self.lod_bands.last())
+            .map_or(1, |band| band.stride);
+        Lod { stride, skirt_depth: self.skirt_depth }
+    }
+    /// Returns the level of detail for the chunk, seen from the given position
+    fn lod_for_chunk(&self, chunk: ChunkCoord, viewer: Vec3) -> Lod {
+        // distance to the closest point of the chunk in the horizontal plane
+        let size = self.chunk_size as f32;
+        let (min_x, min_z) = self.layout().chunk_to_world(chunk);
+        let (min_x, min_z) = (min_x as f32, min_z as f32);
+        let dx = (min_x - viewer.x).max(viewer.x - (min_x + size)).max(0.0);
+        let dz = (min_z - viewer.z).max(viewer.z - (min_z + size)).max(0.0);
+        self.lod_for_distance(Vec2::new(dx, dz).length())
     }
-    fn get_colormap(&self) -> Handle<Image> {
-        self.colormap.as_ref().unwrap().clone()
+    /// Uses the elevation map as terrain source, with `texels_per_unit` texels per world unit
+    fn set_map(&mut self, map: Arc<ElevationMap>) {
+        let texel_size = self.layout().texel_size();
+        self.source = Option::Some(Arc::new(ImageSource::new(map.clone(), (texel_size, texel_size))));
+        self.map = Option::Some(map);
+    }
+    /// Returns the size (x, z) in world units covered by the colormap, which is the whole elevation map
+    fn uv_extent(&self) -> (f64, f64) {
+        match &self.map {
+            Some(map) => (map.size().0 as f64 / self.texels_per_unit, map.size().1 as f64 / self.texels_per_unit),
+            None => (self.chunk_size, self.chunk_size),
+        }
+    }
+    /// Frees the meshes of all cached chunks, which are outdated after the terrain source changed
+    fn clear_cache(&mut self, meshes: &mut Assets<Mesh>) {
+        if let Some(cache) = self.chunk_cache.as_mut() {
+            for (_, chunk) in cache.drain() {
+                meshes.remove(&chunk.mesh);
+            }
+        }
+    }
+    /// Returns a terrain source sampling a copy of the texels of the chunk (see `ChunkLayout::chunk_texels`)
+    fn chunk_region<M: Heightmap + ?Sized>(layout: ChunkLayout, map: &M, chunk: ChunkCoord) -> ImageSource {
+        let (columns, rows) = layout.chunk_texels(chunk);
+        let origin = (*columns.start(), *rows.start());
+        let texel_size = layout.texel_size();
+        ImageSource::new(Arc::new(map.region(columns, rows)), (texel_size, texel_size)).with_origin(origin)
+    }
+    /// Starts generating the mesh and collider of the chunk in the background.
+    /// The elevation map is edited in place, so the task gets a copy of the texels of the chunk instead of the map.
+    /// The texels of a tiled map are read in the task, as reading its tiles may have to wait for the file.
+    fn spawn_chunk_task(&self, chunk: ChunkCoord, lod: Lod) -> PendingChunk {
+        let layout = self.layout();
+        let mesh_pos = layout.chunk_to_cell(chunk);
+        let mesh_size = (self.chunk_resolution, self.chunk_resolution);
+        let source: Box<dyn FnOnce() -> Arc<dyn TerrainSource> + Send> = match (&self.map, &self.tiled_map) {
+            (Some(map), _) => {
+                let source = Arc::new(Terrain::chunk_region(layout, map.as_ref(), chunk));
+                Box::new(move || source)
+            },
+            (None, Some(map)) => {
+                let map = map.clone();
+                Box::new(move || Arc::new(Terrain::chunk_region(layout, map.as_ref(), chunk)))
+            },
+            (None, None) => {
+                let source = self.get_source();
+                Box::new(move || source)
+            },
+        };
+        let (size, intensity, uv_extent) = (self.chunk_size, self.intensity, self.uv_extent());
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            create_chunk(size, mesh_pos, mesh_size, source().as_ref(), intensity, lod, uv_extent)
+        });
+        PendingChunk(task)
     }
 }
 impl Default for Terrain {
     fn default() -> Self {
         Terrain { 
-            size: Terrain::DEFAULT_SIZE,
+            chunk_size: Terrain::DEFAULT_CHUNK_SIZE,
             intensity: Terrain::DEFAULT_INTENSITY,
+            kind: TerrainKind::default(),
             colormap: Option::None,
+            elevation_map: Handle::default(),
             map: Option::None,
-            mesh_size: (0, 0),
-            entity_map: HashMap::default()
+            base_map: Option::None,
+            tiled_map: Option::None,
+            source: Option::None,
+            chunk_resolution: Terrain::DEFAULT_CHUNK_RESOLUTION,
+            texels_per_unit: Terrain::DEFAULT_TEXELS_PER_UNIT,
+            entity_map: HashMap::default(),
+            load_radius: Terrain::DEFAULT_LOAD_RADIUS,
+            unload_radius: Terrain::DEFAULT_UNLOAD_RADIUS,
+            chunk_cache: Option::Some(LruCache::new(Terrain::DEFAULT_CHUNK_CACHE_SIZE)),
+            max_chunks_per_frame: Terrain::DEFAULT_MAX_CHUNKS_PER_FRAME,
+            lod_bands: Terrain::DEFAULT_LOD_BANDS.to_vec(),
+            skirt_depth: Terrain::DEFAULT_SKIRT_DEPTH,
+            hydraulic_erosion: Option::None,
+            thermal_erosion: Option::None,
+            history: EditHistory::new(Terrain::DEFAULT_HISTORY_BUDGET),
+        }
+    }
+}
+
+/// Kind of terrain, selected with the `--terrain <image|tiled|noise|world|flat>` command line argument
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum TerrainKind {
+    /// elevation map loaded from the assets
+    #[default]
+    Image,
+    /// tiled elevation map file given with `--tiled-file <path>`, e.g. saved with `terrain-tool --save-tiled`
+    Tiled,
+    /// fractal noise, generated while creating the chunks
+    Noise,
+    /// endless, non-repeating world from seeded and domain-warped noise
+    World,
+    /// flat plane
+    Flat,
+}
+impl TerrainKind {
+    fn from_args() -> Self {
+        match arg_value("--terrain").as_deref() {
+            None | Some("image") => TerrainKind::Image,
+            Some("tiled") => TerrainKind::Tiled,
+            Some("noise") => TerrainKind::Noise,
+            Some("world") => TerrainKind::World,
+            Some("flat") => TerrainKind::Flat,
+            Some(kind) => {
+                eprintln!("Unknown terrain kind {kind}, expected image, tiled, noise, world or flat");
+                TerrainKind::Image
+            },
+        }
+    }
+}
+
+/// Chunks closer to the camera than `max_distance` use every `stride`-th elevation value as vertex
+#[derive(Clone, Copy, Debug)]
+struct LodBand {
+    max_distance: f32,
+    stride: usize,
+}
+
+/// Mesh and collider of an unloaded terrain chunk, kept for reuse
+struct CachedChunk {
+    mesh: Handle<Mesh>,
+    collider: Collider,
+    lod: Lod,
+}
+
+/// Terrain chunk which is still being generated in the background.
+/// The entity is a placeholder until the task has finished.
+#[derive(Component)]
+struct PendingChunk(Task<(Mesh, Collider)>);
+
+/// Pending terrain chunk whose elevation values were edited while it was being generated,
+/// it is generated again once its task has finished.
+#[derive(Component)]
+struct DirtyChunk;
+
+/// Seed of every random source of the world (cube placement, terrain noise, ...),
+/// so the same seed always creates the same world.
+/// Set with the `--seed <number>` command line argument or the `WORLD_SEED` environment variable,
+/// otherwise it is chosen randomly (and shown in the debug window, to reproduce the world).
+#[derive(Resource, Clone, Copy, Debug, PartialEq)]
+pub struct WorldSeed(pub u64);
+impl WorldSeed {
+    fn from_args_or_env() -> Self {
+        let seed = arg_value("--seed").or_else(|| std::env::var("WORLD_SEED").ok());
+        match seed.map(|seed| seed.trim().parse::<u64>()) {
+            Some(Ok(seed)) => WorldSeed(seed),
+            Some(Err(err)) => {
+                eprintln!("Invalid world seed ({err}), using a random one");
+                WorldSeed(rand::random())
+            },
+            None => WorldSeed(rand::random()),
         }
     }
+    const CUBES:u64 = 1;
+    const EROSION:u64 = 2;
+
+    /// Returns the seed for one use of the world seed,
+    /// so adding another random source doesn't change the existing ones.
+    fn derive(&self, stream: u64) -> u64 {
+        self.0 ^ stream.wrapping_mul(0x9e37_79b9_7f4a_7c15)
+    }
+    /// Returns a random number generator for one use of the world seed, see `derive`
+    fn rng(&self, stream: u64) -> StdRng {
+        StdRng::seed_from_u64(self.derive(stream))
+    }
+    /// Returns the seed for the noise functions of the noise crate
+    fn noise_seed(&self) -> u32 {
+        (self.0 ^ (self.0 >> 32)) as u32
+    }
+}
+
+/// Returns the hydraulic erosion given by the `--erode <droplets>` command line argument
+fn hydraulic_erosion_from_args() -> Option<HydraulicErosion> {
+    let droplets = arg_value("--erode")?;
+    match droplets.parse() {
+        Ok(droplets) => Some(HydraulicErosion { droplets, ..default() }),
+        Err(err) => {
+            eprintln!("Invalid number of erosion droplets ({err}), erosion disabled");
+            None
+        },
+    }
+}
+
+/// Returns the thermal erosion given by the `--thermal <iterations>` command line argument
+fn thermal_erosion_from_args() -> Option<ThermalErosion> {
+    let iterations = arg_value("--thermal")?;
+    match iterations.parse() {
+        Ok(iterations) => Some(ThermalErosion { iterations, ..default() }),
+        Err(err) => {
+            eprintln!("Invalid number of thermal erosion iterations ({err}), erosion disabled");
+            None
+        },
+    }
+}
+
+/// Ground queries for gameplay systems, e.g. `fn system(ground: Ground)`
+#[derive(SystemParam)]
+struct Ground<'w> {
+    terrain: Res<'w, Terrain>,
+}
+impl Ground<'_> {
+    /// Returns the height of the ground at the world position (x, z), or None while the terrain isn't loaded
+    fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        self.terrain.height_at(x, z)
+    }
+    /// Returns the normal of the ground at the world position (x, z), or None while the terrain isn't loaded
+    fn normal_at(&self, x: f32, z: f32) -> Option<Vec3> {
+        self.terrain.normal_at(x, z)
+    }
 }
 
+/// Error message of a failed elevation map loading, shown on screen
+#[derive(Resource)]
+struct TerrainLoadError(String);
+
 #[derive(Component,Debug)]
 struct TerrainMesh {
-    _x: isize,
-    _y: isize,
+    coord: ChunkCoord,
+    lod: Lod,
 }
 impl TerrainMesh {
-    fn new(x :isize,y :isize) -> Self {
+    fn new(coord :ChunkCoord,lod :Lod) -> Self {
         TerrainMesh {
-            _x: x,
-            _y: y,
+            coord,
+            lod,
         }
     }
 }
@@ -152,42 +511,64 @@ fn setup(
     mut materials: ResMut<Assets<StandardMaterial>>,
     asset_server: Res<AssetServer>,
     mut terrain: ResMut<Terrain>,
-    //seed: Res<Seed>,
+    seed: Res<WorldSeed>,
 ) {
     
     // // plane
     // let _plane_entity = commands.spawn(PbrBundle {
-    //         mesh: meshes.add(shape::Box::new(terrain.size as f32, 0.1, terrain.size as f32).into()),
+    //         mesh: meshes.add(shape::Box::new(terrain.chunk_size as f32, 0.1, terrain.chunk_size as f32).into()),
     //         material: materials.add(Color::rgb(0.3, 0.5, 0.3).into()),
     //         transform: Transform::from_xyz(0.0, -0.1, 0.0),
     //         ..default()
     //     })
     //     .insert(Name::new("Plane"))
-    //     .insert(Collider::cuboid(terrain.size as f32/2.0, 0.05, terrain.size as f32/2.0))
+    //     .insert(Collider::cuboid(terrain.chunk_size as f32/2.0, 0.05, terrain.chunk_size as f32/2.0))
     //     .id();
     
     // terrain
 
-    // // randomly generated noisemap
-    // let width: usize = 512;
-    // let depth: usize = 512;
-    // let frequency = 0.1;
-    // let lacunarity = 2.0;
-    // let octaves = 6;
-    // let create_file = true;
-    // let noisemap = generate_noisemap(terrain.size, width, depth, frequency, lacunarity, octaves, create_file);
-    // let elevation_map: Handle<Image> = asset_server.load("fbm.png").into();
-    // let map = load_elevation_map( "example_images/fbm.png", 2.0);
-
-    // Initialize terrain creation
-    // staigermanus dogwaffle terrain3 map https://www.renderosity.com/freestuff/items/77673
-    // 1024 * 768 = (2^10) * (3*2^8)
-    let color_map: Handle<Image> = asset_server.load("dogwaffle-terrain3/dogwaffle-terrain3-colr.png").into();
-    let map = load_elevation_map("assets/dogwaffle-terrain3/dogwaffle-terrain3-elev.png", 4.0);
-    let (width, depth) = map.size();
-    terrain.colormap = Option::Some(color_map);
-    terrain.map = Option::Some(map);
-    terrain.mesh_size = (width, depth);
+    match terrain.kind {
+        TerrainKind::Image => {
+            // Initialize terrain creation, see elevation_map_update
+            // staigermanus dogwaffle terrain3 map https://www.renderosity.com/freestuff/items/77673
+            // 1024 * 768 = (2^10) * (3*2^8)
+            // (the loader settings are in the .meta file next to the elevation map)
+            let color_map: Handle<Image> = asset_server.load("dogwaffle-terrain3/dogwaffle-terrain3-colr.png");
+            terrain.colormap = Option::Some(color_map);
+            terrain.elevation_map = asset_server.load("dogwaffle-terrain3/dogwaffle-terrain3-elev.png");
+        },
+        TerrainKind::Tiled => {
+            let budget = arg_parse("--tile-budget").unwrap_or(Terrain::DEFAULT_TILE_BUDGET_MB) * 1024 * 1024;
+            let result = arg_value("--tiled-file")
+                .ok_or_else(|| "no file given with --tiled-file".to_string())
+                .and_then(|path| TiledElevationMap::open(&path, budget).map_err(|err| format!("{path}: {err}")));
+            match result {
+                Ok(map) => {
+                    let map = Arc::new(map);
+                    let texel_size = terrain.layout().texel_size();
+                    terrain.source = Option::Some(Arc::new(ImageSource::new(map.clone(), (texel_size, texel_size))));
+                    terrain.tiled_map = Option::Some(map);
+                },
+                Err(err) => {
+                    eprintln!("Falling back to flat terrain, the tiled elevation map couldn't be loaded: {err}");
+                    commands.insert_resource(TerrainLoadError(err));
+                    terrain.source = Option::Some(Arc::new(PlaneSource::constant(0.0)));
+                },
+            }
+        },
+        TerrainKind::Noise => {
+            let scale = 1.0 / Terrain::NOISE_FEATURE_SIZE;
+            let source = NoiseSource::new(seed.noise_seed(), 0.5, 2.0, 6, scale, Terrain::GENERATED_MAX_HEIGHT);
+            terrain.source = Option::Some(Arc::new(source));
+        },
+        TerrainKind::World => {
+            let source = WorldSource::new(seed.0, Terrain::WORLD_FEATURE_SIZE, Terrain::WORLD_MAX_HEIGHT);
+            terrain.source = Option::Some(Arc::new(source));
+        },
+        TerrainKind::Flat => {
+            terrain.source = Option::Some(Arc::new(PlaneSource::constant(0.0)));
+        },
+    }
 
 
     // Create the ball
@@ -215,7 +596,7 @@ fn setup(
 
     // Create cubes as childs of the ball
     let cube_count = 50;
-    let mut rng = rand::thread_rng();
+    let mut rng = seed.rng(WorldSeed::CUBES);
     for i in 1..=cube_count {
         let mut position = Transform::from_xyz(rng.gen_range(1.0..2.0),rng.gen_range(-0.25..0.25),0.0);
         position.translate_around(Vec3::ZERO, Quat::from_axis_angle(Vec3::Y, -TAU / cube_count as f32 * i as f32));
@@ -261,52 +642,248 @@ fn setup(
 }
 
 
-/// Dynamically create and update terrain map
+/// Dynamically create, update and unload terrain map
 fn map_update(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     mut terrain: ResMut<Terrain>,
-    ball_query: Query<&Transform, (With<MovableBall>,Without<MovableCube>,Without<CameraControl>)>,
+    ball_query: Query<&Transform, BallFilter>,
+    camera_query: Query<&GlobalTransform, With<CameraControl>>,
+    chunk_query: Query<(&Handle<Mesh>, &Handle<StandardMaterial>, &Collider, &TerrainMesh)>,
 ) {
+    if terrain.source.is_none() {
+        // elevation map isn't loaded yet
+        return;
+    }
     let ball_transform = ball_query.single();
-    let (width, depth) = terrain.mesh_size;
     // Player position
-    let px = (ball_transform.translation.x / terrain.size as f32) as isize % width as isize;
-    let py = (ball_transform.translation.z / terrain.size as f32) as isize % depth as isize;
-
-    // Check and create (if necessary) 5*5 terrain meshes around player
-    for x in -2+px..=2+px {
-        for y in -2+py..=2+py {
-            // if entity_map doesn't contain the key, create a new mesh
-            if !terrain.entity_map.contains_key(&(x as usize, y as usize)) {
-                println!("Creating new mesh at [{x}][{y}]", x=x, y=y);
-                let mesh = create_mesh(terrain.size, (x * width as isize, y * depth as isize), (width, depth), terrain.get_map(), terrain.intensity);
-                let mesh_entity = commands.spawn(PbrBundle {
-                        mesh: meshes.add(mesh),
-                        material: materials.add(terrain.get_colormap().into()),
-                        transform: Transform::from_xyz(0.0, -0., 0.0),
-                        ..Default::default()
-                    })
-                    .insert(TerrainMesh::new(x, y))
-                    //.insert(Collider::from_bevy_mesh(&mesh,ComputedColliderShape::TriMesh))
+    let player = terrain.layout().position_to_chunk(ball_transform.translation);
+
+    // Unload terrain meshes outside of the unload radius, keeping the recently used ones cached
+    let unload_keys = chunks_to_unload(terrain.entity_map.keys(), player, terrain.load_radius, terrain.unload_radius);
+    for key in unload_keys {
+        let Some(entity) = terrain.entity_map.remove(&key) else { continue };
+        println!("Unloading mesh at {key}");
+        if let Ok((mesh, material, collider, chunk)) = chunk_query.get(entity) {
+            materials.remove(material);
+            let cached = CachedChunk { mesh: mesh.clone(), collider: collider.clone(), lod: chunk.lod };
+            let evicted = match terrain.chunk_cache.as_mut() {
+                Some(cache) => cache.put(key, cached),
+                None => vec![(key, cached)],
+            };
+            for (_, chunk) in evicted {
+                meshes.remove(&chunk.mesh);
+            }
+        }
+        commands.entity(entity).despawn_recursive();
+    }
+
+    // Check and create (if necessary) terrain meshes within the load radius around player
+    let load_radius = terrain.load_radius;
+    for dx in -load_radius..=load_radius {
+        for dy in -load_radius..=load_radius {
+            // if entity_map doesn't contain the key, create a new mesh (or reuse a cached one)
+            let key = player.offset(dx, dy);
+            if !terrain.entity_map.contains_key(&key) {
+                let lod = terrain.lod_for_chunk(key, camera_query.single().translation());
+                let mut mesh_entity = commands.spawn(SpatialBundle::default());
+                mesh_entity
                     //.insert(Wireframe)
-                    .insert(Name::new(format!("TerrainMesh[{x}][{y}]")))
-                    .id();
-                terrain.entity_map.insert((x as usize, y as usize), mesh_entity);
+                    .insert(Name::new(format!("TerrainMesh{key}")));
+                match terrain.chunk_cache.as_mut().and_then(|cache| cache.take(&key)) {
+                    Some(chunk) => {
+                        // a cached mesh with another level of detail gets rebuilt in the next frame
+                        println!("Reusing cached mesh at {key}");
+                        mesh_entity
+                            .insert(TerrainMesh::new(key, chunk.lod))
+                            .insert(PbrBundle {
+                                mesh: chunk.mesh,
+                                material: materials.add(terrain.get_material()),
+                                ..Default::default()
+                            })
+                            .insert(chunk.collider);
+                    },
+                    None => {
+                        // generate mesh and collider in the background, see finalize_chunks
+                        println!("Creating new mesh at {key}");
+                        mesh_entity
+                            .insert(TerrainMesh::new(key, lod))
+                            .insert(terrain.spawn_chunk_task(key, lod));
+                    },
+                }
+                let mesh_entity = mesh_entity.id();
+                terrain.entity_map.insert(key, mesh_entity);
             }
         }
     }
 }
 
 
+/// Take over the elevation map asset whenever it has been (re)loaded and regenerate all loaded chunks.
+/// If it fails to load, a generated elevation map is used instead.
+#[allow(clippy::too_many_arguments)]
+fn elevation_map_update(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain: ResMut<Terrain>,
+    mut events: EventReader<AssetEvent<ElevationMap>>,
+    elevation_maps: Res<Assets<ElevationMap>>,
+    asset_server: Res<AssetServer>,
+    loader: Res<ElevationMapLoader>,
+    seed: Res<WorldSeed>,
+    chunk_query: Query<(Entity, &TerrainMesh)>,
+) {
+    let id = terrain.elevation_map.id();
+    let changed = events.read().any(|event| event.is_loaded_with_dependencies(id) || event.is_modified(id));
+    let map = match elevation_maps.get(id) {
+        Some(map) if changed => map.clone(),
+        None if terrain.map.is_none() && asset_server.load_state(id) == LoadState::Failed => {
+            // fall back to a randomly generated (seamless) noisemap
+            let path = asset_server.get_path(id).map_or_else(String::new, |path| path.to_string());
+            let err = loader.error(&path).unwrap_or_else(|| "couldn't load elevation map".to_string());
+            eprintln!("Falling back to generated terrain, elevation map {path} couldn't be loaded: {err}");
+            commands.insert_resource(TerrainLoadError(format!("{path}: {err}")));
+            let width: usize = 512;
+            let depth: usize = 512;
+            let frequency = 0.1;
+            let lacunarity = 2.0;
+            let octaves = 6;
+            let noisemap = generate_noisemap(seed.noise_seed(), Terrain::NOISE_FEATURE_SIZE, width, depth, frequency, lacunarity, octaves, None);
+            noisemap_to_elevation_map(&noisemap, 4.0)
+        },
+        _ => return,
+    };
+    if changed {
+        println!("Elevation map (re)loaded, regenerating terrain");
+        commands.remove_resource::<TerrainLoadError>();
+    }
+
+    let mut map = map;
+    if let Some(erosion) = &terrain.thermal_erosion {
+        println!("Eroding elevation map with {} thermal iterations", erosion.iterations);
+        // the talus angle applies to the terrain in the real world
+        let texel_size = terrain.layout().texel_size() / terrain.intensity as f64;
+        erode_thermal(&mut map, &ThermalErosion { texel_size, ..erosion.clone() });
+    }
+    if let Some(erosion) = &terrain.hydraulic_erosion {
+        println!("Eroding elevation map with {} droplets", erosion.droplets);
+        erode_hydraulic(&mut map, erosion, seed.derive(WorldSeed::EROSION), false);
+    }
+    let map = Arc::new(map);
+    terrain.base_map = Option::Some(map.clone());
+    terrain.set_map(map);
+    // edits of the previous map can't be undone anymore
+    terrain.history.clear();
+    // cached meshes are outdated, and all loaded chunks get regenerated in the background
+    terrain.clear_cache(&mut meshes);
+    for (entity, chunk) in &chunk_query {
+        commands.entity(entity).insert(terrain.spawn_chunk_task(chunk.coord, chunk.lod));
+    }
+}
+
+
+/// Rebuild loaded terrain meshes whose level of detail changed
+fn chunk_lod_update(
+    mut commands: Commands,
+    terrain: Res<Terrain>,
+    camera_query: Query<&GlobalTransform, With<CameraControl>>,
+    mut chunk_query: Query<(Entity, &mut TerrainMesh)>,
+) {
+    let viewer = camera_query.single().translation();
+    for (entity, mut chunk) in &mut chunk_query {
+        let lod = terrain.lod_for_chunk(chunk.coord, viewer);
+        if chunk.lod != lod {
+            println!("Rebuilding mesh at {} with stride {}", chunk.coord, lod.stride);
+            chunk.lod = lod;
+            commands.entity(entity).insert(terrain.spawn_chunk_task(chunk.coord, lod));
+        }
+    }
+}
+
+
+/// Insert the generated terrain meshes of finished background tasks,
+/// but not more than `Terrain::max_chunks_per_frame` per frame.
+/// Meshes of rebuilt chunks are replaced and the old ones freed, dirty chunks are generated again.
+fn finalize_chunks(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    terrain: Res<Terrain>,
+    mut pending_query: Query<PendingChunkData>,
+) {
+    let finished = pending_query.iter_mut()
+        .filter(|(_, pending, _, _, _)| pending.0.is_finished())
+        .take(terrain.max_chunks_per_frame);
+    for (entity, mut pending, old_mesh, chunk, dirty) in finished {
+        let (mesh, collider) = block_on(&mut pending.0);
+        let mut chunk_entity = commands.entity(entity);
+        if dirty {
+            // the edited chunk is shown now and generated again with the latest edits
+            chunk_entity.remove::<DirtyChunk>().insert(terrain.spawn_chunk_task(chunk.coord, chunk.lod));
+        } else {
+            chunk_entity.remove::<PendingChunk>();
+        }
+        chunk_entity.insert(collider);
+        match old_mesh {
+            Some(old_mesh) => {
+                meshes.remove(old_mesh);
+                chunk_entity.insert(meshes.add(mesh));
+            },
+            None => {
+                chunk_entity.insert(PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: materials.add(terrain.get_material()),
+                    ..Default::default()
+                });
+            },
+        }
+    }
+}
+
+
+/// Export the loaded terrain chunks (as OBJ and glTF) and the elevation map (as 16-bit PNG and raw R32)
+/// into `Terrain::EXPORT_DIRECTORY` when F12 is pressed
+fn export_terrain(
+    input: Res<Input<KeyCode>>,
+    terrain: Res<Terrain>,
+    meshes: Res<Assets<Mesh>>,
+    chunk_query: Query<(&Handle<Mesh>, &TerrainMesh)>,
+) {
+    if !input.just_pressed(KeyCode::F12) {
+        return;
+    }
+    let directory = std::path::Path::new(Terrain::EXPORT_DIRECTORY);
+    let chunks: Vec<(String, &Mesh)> = terrain.entity_map.values()
+        .filter_map(|entity| chunk_query.get(*entity).ok())
+        .filter_map(|(mesh, chunk)| Some((format!("TerrainMesh{}", chunk.coord), meshes.get(mesh)?)))
+        .collect();
+    let result = std::fs::create_dir_all(directory)
+        .and_then(|_| write_obj(&chunks, std::fs::File::create(directory.join("terrain.obj"))?))
+        .and_then(|_| write_glb(&chunks, std::fs::File::create(directory.join("terrain.glb"))?))
+        .and_then(|_| match &terrain.map {
+            Some(map) => {
+                let max_height = map.range().1.max(f64::EPSILON);
+                save_elevation_map_png16(map, directory.join("elevation.png"), max_height)?;
+                save_elevation_map_r32(map, directory.join("elevation.r32"), max_height)
+            },
+            None => Ok(()),
+        });
+    match result {
+        Ok(()) => println!("Exported {} terrain chunks to {}", chunks.len(), directory.display()),
+        Err(err) => eprintln!("Terrain export failed: {err}"),
+    }
+}
+
+
 /// handle user input
 fn user_actions(
     input: Res<Input<KeyCode>>,
     time: Res<Time>,
     mut ev_motion: EventReader<MouseMotion>,
     res_buttons:  Res<Input<MouseButton>>,
-    mut ball_query: Query<(&mut MovableBall, &mut Transform, &mut Velocity), (With<MovableBall>,Without<MovableCube>,Without<CameraControl>)>,
+    ground: Ground,
+    mut ball_query: Query<(&mut MovableBall, &mut Transform, &mut Velocity), BallFilter>,
     camera_query: Query<&Transform, With<CameraControl>>
 ) {
     let (mut ball, mut ball_transform, mut velocity) = ball_query.single_mut();
@@ -315,13 +892,21 @@ fn user_actions(
             camera_transform.translation.x, 0.0, camera_transform.translation.z 
         ));
         
-    if ball_transform.translation.y < MovableBall::DEATH_HEIGHT {
-        ball_transform.translation = MovableBall::INITIAL_POSITION.translation;
+    // the ground is at 0.0 until the terrain is loaded
+    let ground_height = |translation: Vec3| ground.height_at(translation.x, translation.z).unwrap_or(0.0);
+    if ball_transform.translation.y < ground_height(ball_transform.translation) + MovableBall::DEATH_HEIGHT {
+        let mut spawn = MovableBall::INITIAL_POSITION.translation;
+        spawn.y = spawn.y.max(ground_height(spawn) + MovableBall::RADIUS + MovableBall::SPAWN_CLEARANCE);
+        ball_transform.translation = spawn;
     }
 
     //
     // input based movement, but only when touching ground
-    if MovableBall::FLY_MODE || ball_transform.translation.y <= MovableBall::RADIUS {
+    // on a slope the center of the resting ball is RADIUS / cos(slope) above the ground below it
+    let translation = ball_transform.translation;
+    let ground_normal = ground.normal_at(translation.x, translation.z).unwrap_or(Vec3::Y);
+    let on_ground = translation.y - ground_height(translation) <= MovableBall::RADIUS / ground_normal.y + MovableBall::GROUND_TOLERANCE;
+    if MovableBall::FLY_MODE || on_ground {
 
         // accumulate key-based movement
         let mut direction = Vec3::ZERO;
@@ -394,7 +979,7 @@ fn user_actions(
 fn cube_orbit_movement(
     time: Res<Time>,
     mut cube_query: Query<&mut Transform, With<MovableCube>>,
-    ball_query: Query<&MovableBall, (With<MovableBall>,Without<MovableCube>,Without<CameraControl>)>
+    ball_query: Query<&MovableBall, BallFilter>
 ) {
     let ball = ball_query.get_single().unwrap();
     let rotation_speed = ball.orbit_speed.current_value();
@@ -419,5 +1004,5 @@ fn calc_rainbow_color(min :usize, max :usize, val :usize) -> Color {
     let min_hue = 360.0;
     let max_hue = 0.0;
     let cur_percent = (val - min) as f32 / (max - min) as f32;
-    Color::hsl(((cur_percent * (max_hue-min_hue) ) + min_hue) as f32, 1.0, 0.5)
+    Color::hsl((cur_percent * (max_hue-min_hue) ) + min_hue, 1.0, 0.5)
 }
@@ -1,20 +1,48 @@
-use image::io::Reader as ImageReader;
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+use image::ColorType;
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
 use bevy::prelude::*;
+use bevy::utils::{BoxedFuture, HashMap};
 use bevy::render::render_resource::PrimitiveTopology;
-use noise::{utils::*, Fbm, Perlin};
+use bevy_rapier3d::prelude::Collider;
+use noise::{utils::*, Fbm, MultiFractal, Perlin};
+use serde::{Deserialize, Serialize};
+use tiff::decoder::DecodingResult;
+use crate::export::save_elevation_map_png16;
+use crate::source::TerrainSource;
+
+/// Defines how an `ElevationMap` is sampled outside of its borders.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum EdgeMode {
+    /// The map is repeated, so the right border continues with the left one.
+    #[default]
+    Wrap,
+    /// The border values are extended infinitely.
+    Clamp,
+    /// The map is mirrored at its borders (without repeating the border values).
+    Mirror,
+    /// Everything outside of the map has the given elevation.
+    SeaLevel(f64),
+}
 
 /// Represents an elevation map with a given size and elevation values.
+/// Elevation maps can be loaded as assets with the `ElevationMapLoader`.
+#[derive(Asset, TypePath, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ElevationMap {
     size: (usize, usize),
     map: Vec<f64>,
+    edge_mode: EdgeMode,
 }
 
 impl ElevationMap {
     /// Creates a new `ElevationMap` with the specified width and height.
-    pub fn _new(width: usize, height: usize) -> Self {
+    pub fn new(width: usize, height: usize) -> Self {
         Self {
             size: (width, height),
             map: vec![0.0; width * height],
+            edge_mode: EdgeMode::default(),
         }
     }
 
@@ -25,14 +53,36 @@ impl ElevationMap {
         Self {
             size: (width, height),
             map,
+            edge_mode: EdgeMode::default(),
         }
     }
 
+    /// Returns the elevation map with the given edge mode.
+    pub fn with_edge_mode(mut self, edge_mode: EdgeMode) -> Self {
+        self.edge_mode = edge_mode;
+        self
+    }
+
+    /// Returns the edge mode of the elevation map.
+    pub fn edge_mode(&self) -> EdgeMode {
+        self.edge_mode
+    }
+
     /// Returns the size (width, height) of the elevation map.
     pub fn size(&self) -> (usize, usize) {
         self.size
     }
 
+    /// Returns the elevation values, row by row.
+    pub fn values(&self) -> &[f64] {
+        &self.map
+    }
+
+    /// Returns the mutable elevation values, row by row.
+    pub fn values_mut(&mut self) -> &mut [f64] {
+        &mut self.map
+    }
+
     /// Sets the elevation value at the specified position (x, y).
     /// Prints an error message if the position is out of bounds.
     pub fn _set_value(&mut self, x: usize, y: usize, value: f64) {
@@ -45,54 +95,373 @@ impl ElevationMap {
         }
     }
 
+    /// Resolves the specified position (x, y) to a position within the map, see `Heightmap::resolve`.
+    pub fn resolve(&self, x: isize, y: isize) -> Option<(usize, usize)> {
+        Heightmap::resolve(self, x, y)
+    }
+
+    /// Returns the elevation value at the specified position (x, y), see `Heightmap::get_value`.
+    pub fn get_value(&self, x: isize, y: isize) -> f64 {
+        Heightmap::get_value(self, x, y)
+    }
+
+    /// Returns the bilinear interpolated elevation value at the specified position (x, y), see `Heightmap::sample`.
+    pub fn sample(&self, x: f64, y: f64) -> f64 {
+        Heightmap::sample(self, x, y)
+    }
+}
+
+/// Read access to the elevation values of a map, which is either an `ElevationMap` in memory
+/// or a `TiledElevationMap` paged in from disk.
+pub trait Heightmap: Send + Sync {
+    /// Returns the size (width, height) of the map.
+    fn size(&self) -> (usize, usize);
+
+    /// Returns the edge mode of the map.
+    fn edge_mode(&self) -> EdgeMode;
+
+    /// Returns the elevation value at the position (x, y) within the map.
+    fn value(&self, x: usize, y: usize) -> f64;
+
+    /// Resolves the specified position (x, y) to a position within the map, according to the edge mode.
+    /// Returns `None` if the position is outside of the map and the edge mode is `EdgeMode::SeaLevel`.
+    fn resolve(&self, x: isize, y: isize) -> Option<(usize, usize)> {
+        let (width, height) = (self.size().0 as isize, self.size().1 as isize);
+        if (0..width).contains(&x) && (0..height).contains(&y) {
+            return Some((x as usize, y as usize));
+        }
+        let (x, y) = match self.edge_mode() {
+            EdgeMode::Wrap => (x.rem_euclid(width), y.rem_euclid(height)),
+            EdgeMode::Clamp => (x.clamp(0, width - 1), y.clamp(0, height - 1)),
+            EdgeMode::Mirror => (mirror(x, width), mirror(y, height)),
+            EdgeMode::SeaLevel(_) => return None,
+        };
+        Some((x as usize, y as usize))
+    }
+
     /// Returns the elevation value at the specified position (x, y).
-    /// If the position is out of bounds, prints an error message and returns -1.0.
-    pub fn get_value(&self, x: usize, y: usize) -> f64 {
-        let (width, height) = self.size;
+    /// Positions outside of the map are sampled according to the edge mode.
+    fn get_value(&self, x: isize, y: isize) -> f64 {
+        match (self.resolve(x, y), self.edge_mode()) {
+            (Some((x, y)), _) => self.value(x, y),
+            (None, EdgeMode::SeaLevel(level)) => level,
+            (None, _) => unreachable!("only the sea level edge mode leaves the map"),
+        }
+    }
 
-        if x < width && y < height {
-            self.map[x + y * width]
-        } else if (x == width && y <= height) || (y == height && x <= width) {
-            0.0 // normal border
-            // TODO: this probably needs to be changed to the overlapping border value
-        } else {
-            eprintln!("illegal position requested: ({}, {})", x, y);
-            -1.0
+    /// Returns the bilinear interpolated elevation value at the specified position (x, y).
+    /// Positions outside of the map are sampled according to the edge mode.
+    fn sample(&self, x: f64, y: f64) -> f64 {
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as isize, y0 as isize);
+        let top = self.get_value(x0, y0) * (1.0 - fx) + self.get_value(x0 + 1, y0) * fx;
+        let bottom = self.get_value(x0, y0 + 1) * (1.0 - fx) + self.get_value(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+
+    /// Returns a copy of the values at the positions `columns` and `rows` (sampled according to the edge mode),
+    /// e.g. to sample them without holding on to the whole map. The copy is clamped at its borders.
+    fn region(&self, columns: RangeInclusive<isize>, rows: RangeInclusive<isize>) -> ElevationMap {
+        let values = rows.clone().flat_map(|y| columns.clone().map(move |x| self.get_value(x, y))).collect();
+        ElevationMap::new_with_data(columns.count(), rows.count(), values).with_edge_mode(EdgeMode::Clamp)
+    }
+}
+
+impl Heightmap for ElevationMap {
+    fn size(&self) -> (usize, usize) {
+        self.size
+    }
+
+    fn edge_mode(&self) -> EdgeMode {
+        self.edge_mode
+    }
+
+    fn value(&self, x: usize, y: usize) -> f64 {
+        self.map[x + y * self.size.0]
+    }
+}
+
+/// Mirrors the index `i` at the borders of `0..n`, without repeating the border values.
+fn mirror(i: isize, n: isize) -> isize {
+    if n == 1 {
+        return 0;
+    }
+    let period = 2 * (n - 1);
+    let i = i.rem_euclid(period);
+    if i < n { i } else { period - i }
+}
+
+/// Errors which can occur when loading terrain data.
+#[derive(Debug)]
+pub enum TerrainError {
+    /// The file couldn't be read.
+    Io(std::io::Error),
+    /// The file content couldn't be decoded.
+    Decode(Box<dyn std::error::Error + Send + Sync>),
+    /// The decoded image has a pixel format which can't be used as elevation data.
+    UnsupportedFormat(String),
+}
+
+impl std::fmt::Display for TerrainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TerrainError::Io(err) => write!(f, "couldn't read elevation map: {err}"),
+            TerrainError::Decode(err) => write!(f, "couldn't decode elevation map: {err}"),
+            TerrainError::UnsupportedFormat(format) => write!(f, "unsupported elevation map pixel format: {format}"),
+        }
+    }
+}
+
+impl std::error::Error for TerrainError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TerrainError::Io(err) => Some(err),
+            TerrainError::Decode(err) => Some(err.as_ref()),
+            TerrainError::UnsupportedFormat(_) => None,
         }
     }
 }
 
-/// Loads an elevation map from the specified image file and returns an `ElevationMap` object.
+impl From<std::io::Error> for TerrainError {
+    fn from(err: std::io::Error) -> Self {
+        TerrainError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for TerrainError {
+    fn from(err: image::ImageError) -> Self {
+        TerrainError::Decode(Box::new(err))
+    }
+}
+
+impl From<tiff::TiffError> for TerrainError {
+    fn from(err: tiff::TiffError) -> Self {
+        TerrainError::Decode(Box::new(err))
+    }
+}
+
+/// File formats elevation maps can be loaded from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeightmapFormat {
+    /// Any image format supported by the `image` crate (8-bit and 16-bit, gray or color).
+    Image,
+    /// TIFF with 8-bit, 16-bit or 32-bit float gray values.
+    Tiff,
+    /// Headerless little-endian 16-bit unsigned values.
+    RawR16,
+    /// Headerless little-endian 32-bit float values (0.0 to 1.0).
+    RawR32,
+}
+
+impl HeightmapFormat {
+    /// Detects the format from the file extension, or from the header of the file content
+    /// for unknown extensions. Raw formats don't have a header, so they need the extension.
+    pub fn detect(filename: &str, content: &[u8]) -> Self {
+        let extension = std::path::Path::new(filename).extension()
+            .and_then(|extension| extension.to_str())
+            .map(|extension| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("r16") | Some("raw") => HeightmapFormat::RawR16,
+            Some("r32") => HeightmapFormat::RawR32,
+            Some("tif") | Some("tiff") => HeightmapFormat::Tiff,
+            _ if content.starts_with(b"II*\0") || content.starts_with(b"MM\0*") => HeightmapFormat::Tiff,
+            _ => HeightmapFormat::Image,
+        }
+    }
+}
+
+/// Loads an elevation map from the specified file and returns an `ElevationMap` object.
+/// The format is detected from the file extension or content, see `HeightmapFormat::detect`.
+/// Raw files are expected to be square, use `load_raw_elevation_map` for other dimensions.
+/// The maximum height of the map is specified by `max_height`.
+/// This loads the file synchronously, the game itself uses the `ElevationMapLoader`.
+pub fn load_elevation_map(filename: &str, max_height: f64) -> Result<ElevationMap, TerrainError> {
+    let content = std::fs::read(filename)?;
+    let format = HeightmapFormat::detect(filename, &content);
+    decode_elevation_map(&content, format, None, max_height)
+}
+
+/// Loads an elevation map from the specified raw file with the given dimensions (width, height).
+/// The maximum height of the map is specified by `max_height`.
+pub fn load_raw_elevation_map(filename: &str, format: HeightmapFormat, size: (usize, usize), max_height: f64) -> Result<ElevationMap, TerrainError> {
+    let content = std::fs::read(filename)?;
+    decode_elevation_map(&content, format, Some(size), max_height)
+}
+
+/// Decodes an elevation map of the given format from the file content.
+/// Raw formats need the dimensions (width, height), if they aren't given, the map is expected to be square.
+/// Integer values are scaled to `max_height` by their bit depth, float values are expected to range from 0.0 to 1.0.
+/// Color images are converted to their luminance, all other values keep their precision.
+pub fn decode_elevation_map(content: &[u8], format: HeightmapFormat, raw_size: Option<(usize, usize)>, max_height: f64) -> Result<ElevationMap, TerrainError> {
+    let ((width, height), values) = match format {
+        HeightmapFormat::Image => decode_image(content, max_height)?,
+        HeightmapFormat::Tiff => decode_tiff(content, max_height)?,
+        HeightmapFormat::RawR16 => decode_raw::<2>(content, raw_size, |bytes| u16::from_le_bytes(bytes) as f64 * max_height / 65536.0)?,
+        HeightmapFormat::RawR32 => decode_raw::<4>(content, raw_size, |bytes| f32::from_le_bytes(bytes) as f64 * max_height)?,
+    };
+    println!("elevation map loaded with dimension: {:?}", (width, height));
+    Ok(ElevationMap::new_with_data(width, height, values))
+}
+
+fn decode_image(content: &[u8], max_height: f64) -> Result<((usize, usize), Vec<f64>), TerrainError> {
+    let dyn_image = image::load_from_memory(content)?;
+    let size = (dyn_image.width() as usize, dyn_image.height() as usize);
+    let values = match dyn_image.color() {
+        ColorType::L8 | ColorType::La8 | ColorType::Rgb8 | ColorType::Rgba8 => dyn_image
+            .into_luma8().iter().map(|&x| (x as f64) * max_height / 256.0).collect(),
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => dyn_image
+            .into_luma16().iter().map(|&x| (x as f64) * max_height / 65536.0).collect(),
+        other => return Err(TerrainError::UnsupportedFormat(format!("{other:?}"))),
+    };
+    Ok((size, values))
+}
+
+fn decode_tiff(content: &[u8], max_height: f64) -> Result<((usize, usize), Vec<f64>), TerrainError> {
+    let mut decoder = tiff::decoder::Decoder::new(std::io::Cursor::new(content))?;
+    let (width, height) = decoder.dimensions()?;
+    let color_type = decoder.colortype()?;
+    if !matches!(color_type, tiff::ColorType::Gray(_)) {
+        return Err(TerrainError::UnsupportedFormat(format!("TIFF {color_type:?}")));
+    }
+    let values = match decoder.read_image()? {
+        DecodingResult::U8(data) => data.iter().map(|&x| (x as f64) * max_height / 256.0).collect(),
+        DecodingResult::U16(data) => data.iter().map(|&x| (x as f64) * max_height / 65536.0).collect(),
+        DecodingResult::F32(data) => data.iter().map(|&x| (x as f64) * max_height).collect(),
+        DecodingResult::F64(data) => data.iter().map(|&x| x * max_height).collect(),
+        _ => return Err(TerrainError::UnsupportedFormat(format!("TIFF {color_type:?}"))),
+    };
+    Ok(((width as usize, height as usize), values))
+}
+
+fn decode_raw<const N: usize>(content: &[u8], size: Option<(usize, usize)>, value: impl Fn([u8; N]) -> f64) -> Result<((usize, usize), Vec<f64>), TerrainError> {
+    let count = content.len() / N;
+    let (width, height) = match size {
+        Some(size) => size,
+        None => {
+            let side = (count as f64).sqrt().round() as usize;
+            (side, side)
+        },
+    };
+    if width == 0 || height == 0 {
+        return Err(TerrainError::Decode(format!("raw elevation map has no values ({width}x{height})").into()));
+    }
+    if content.len() != width * height * N {
+        return Err(TerrainError::Decode(format!(
+            "raw elevation map has {} bytes, but {width}x{height} values of {N} bytes are expected", content.len()
+        ).into()));
+    }
+    let values = content.chunks_exact(N).map(|bytes| value(bytes.try_into().unwrap())).collect();
+    Ok(((width, height), values))
+}
+
+/// Settings of the `ElevationMapLoader`, usually given in the `.meta` file of the elevation map.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ElevationMapSettings {
+    /// The maximum height of the map.
+    pub max_height: f64,
+    /// The dimensions (width, height) of raw files, see `decode_elevation_map`.
+    pub raw_size: Option<(usize, usize)>,
+    /// The edge mode of the loaded map.
+    pub edge_mode: EdgeMode,
+}
+
+impl Default for ElevationMapSettings {
+    fn default() -> Self {
+        Self {
+            max_height: 1.0,
+            raw_size: None,
+            edge_mode: EdgeMode::default(),
+        }
+    }
+}
+
+/// Loads `ElevationMap` assets in all formats of `HeightmapFormat`.
+/// Raw and TIFF files are recognized by their extension (raw files without a `.meta` file have to be square),
+/// other image formats (like PNG) are claimed by bevy's
+/// image loader, so they need a `.meta` file selecting this loader.
+/// Bevy only reports failed loads as `LoadState::Failed`, so the loader keeps their errors (see `error`).
+/// It is cloned into a resource to look them up, all clones share the errors.
+#[derive(Resource, Clone, Default)]
+pub struct ElevationMapLoader {
+    errors: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl ElevationMapLoader {
+    /// Returns the error of the last load of the asset at `path`, if it failed.
+    pub fn error(&self, path: &str) -> Option<String> {
+        self.errors.lock().unwrap().get(path).cloned()
+    }
+}
+
+impl AssetLoader for ElevationMapLoader {
+    type Asset = ElevationMap;
+    type Settings = ElevationMapSettings;
+    type Error = TerrainError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        settings: &'a ElevationMapSettings,
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<ElevationMap, TerrainError>> {
+        Box::pin(async move {
+            let mut content = Vec::new();
+            let result = match reader.read_to_end(&mut content).await {
+                Ok(_) => {
+                    let filename = load_context.path().to_string_lossy();
+                    let format = HeightmapFormat::detect(&filename, &content);
+                    decode_elevation_map(&content, format, settings.raw_size, settings.max_height)
+                },
+                Err(err) => Err(err.into()),
+            };
+            let mut errors = self.errors.lock().unwrap();
+            match &result {
+                Ok(_) => errors.remove(&load_context.asset_path().to_string()),
+                Err(err) => errors.insert(load_context.asset_path().to_string(), err.to_string()),
+            };
+            Ok(result?.with_edge_mode(settings.edge_mode))
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["r16", "r32", "raw", "tif", "tiff"]
+    }
+}
+
+/// Converts a noise map (with values from -1.0 to 1.0) into an `ElevationMap` object.
 /// The maximum height of the map is specified by `max_height`.
-pub fn load_elevation_map(filename: &str, max_height: f64) -> ElevationMap {
-    let dyn_image = ImageReader::open(filename).unwrap().decode().unwrap();
-    let gray_image = dyn_image.as_luma8().unwrap();
-    println!("image loaded with dimension: {:?}", gray_image.dimensions());
+pub fn noisemap_to_elevation_map(noisemap: &NoiseMap, max_height: f64) -> ElevationMap {
+    let (width, height) = noisemap.size();
     ElevationMap::new_with_data(
-        gray_image.width() as usize,
-        gray_image.height() as usize,
-        gray_image.to_vec().into_iter().map(|x| (x as f64) * max_height / 256.0).collect(),
+        width,
+        height,
+        noisemap.iter().map(|&x| (x.clamp(-1.0, 1.0) + 1.0) * 0.5 * max_height).collect(),
     )
 }
 
 /// Generates a noise map using the Fast Brownian Motion algorithm and returns a `NoiseMap` object.
+/// The same `seed` always generates the same noise map.
 /// The `extent` parameter determines the size of the map.
 /// The `width` and `depth` parameters determine the resolution of the map.
 /// The `frequency`, `lacunarity`, and `octaves` parameters control the characteristics of the noise.
-/// If `create_file` is true, the generated noise map will be saved as "fbm.png".
-pub fn _generate_noisemap(
+/// If a `file` is given, the generated noise map will be saved there as 16-bit PNG.
+#[allow(clippy::too_many_arguments)]
+pub fn generate_noisemap(
+    seed: u32,
     extent: f64,
     width: usize,
     depth: usize,
     frequency: f64,
     lacunarity: f64,
     octaves: usize,
-    create_file: bool,
+    file: Option<&std::path::Path>,
 ) -> NoiseMap {
-    let mut fbm = Fbm::<Perlin>::default();
-    fbm.frequency = frequency;
-    fbm.lacunarity = lacunarity;
-    fbm.octaves = octaves;
+    let fbm = Fbm::<Perlin>::new(seed)
+        .set_frequency(frequency)
+        .set_lacunarity(lacunarity)
+        .set_octaves(octaves);
     let noisemap = PlaneMapBuilder::<Fbm<Perlin>, 2>::new(fbm)
         .set_size(width, depth)
         .set_x_bounds(-extent, extent)
@@ -100,73 +469,227 @@ pub fn _generate_noisemap(
         .set_is_seamless(true)
         .build();
 
-    if create_file {
-        noisemap.write_to_file("fbm.png");
+    if let Some(file) = file {
+        if let Err(err) = save_elevation_map_png16(&noisemap_to_elevation_map(&noisemap, 1.0), file, 1.0) {
+            eprintln!("couldn't save noise map to {}: {err}", file.display());
+        }
     }
     noisemap
 }
 
+/// Level of detail of a terrain chunk.
+/// Only every `stride`-th elevation value is used as vertex, and the borders of the mesh get skirts
+/// hanging down `skirt_depth` world units, which hide cracks to neighbours with another level of detail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Lod {
+    pub stride: usize,
+    pub skirt_depth: f32,
+}
+
+/// Elevation values sampled for a chunk: the offsets of the vertex columns and rows
+/// (relative to the mesh position), the heights, normals and tangents row by row.
+struct ChunkGrid {
+    columns: Vec<usize>,
+    rows: Vec<usize>,
+    heights: Vec<f32>,
+    normals: Vec<[f32; 3]>,
+    tangents: Vec<[f32; 4]>,
+}
+
+/// Returns the vertex offsets along one side of a mesh with `cells` cells for the given stride.
+/// The offsets are evenly distributed and always include both borders, so neighbouring chunks
+/// share their border vertices at any level of detail. They are only evenly spaced if `stride` divides `cells`.
+fn lod_offsets(cells: usize, stride: usize) -> Vec<usize> {
+    let lod_cells = cells.div_ceil(stride.max(1)).max(1);
+    (0..=lod_cells).map(|i| i * cells / lod_cells).collect()
+}
+
+/// Samples the terrain source covered by a mesh at `mesh_pos` with `mesh_size` cells,
+/// using every `stride`-th cell. `cell_size` is the size (x, z) of one cell in the real world.
+/// Normals and tangents are calculated by central differences of the neighbouring elevation values
+/// (independent of the stride), so they are the same on both sides of a chunk border.
+fn sample_heights(mesh_pos: (isize, isize), mesh_size: (usize, usize), stride: usize, cell_size: (f64, f64), source: &dyn TerrainSource, intensity: f32) -> ChunkGrid {
+    let (mesh_width, mesh_depth) = mesh_size;
+    let (mesh_x, mesh_y) = mesh_pos;
+
+    let columns = lod_offsets(mesh_width, stride);
+    let rows = lod_offsets(mesh_depth, stride);
+    let vertices_count = columns.len() * rows.len();
+    let mut heights: Vec<f32> = Vec::with_capacity(vertices_count);
+    let mut normals: Vec<[f32; 3]> = Vec::with_capacity(vertices_count);
+    let mut tangents: Vec<[f32; 4]> = Vec::with_capacity(vertices_count);
+    for d in &rows {
+        for w in &columns {
+            let (x, z) = ((mesh_x + *w as isize) as f64 * cell_size.0, (mesh_y + *d as isize) as f64 * cell_size.1);
+            heights.push(source.height(x, z) as f32);
+
+            let (slope_x, slope_z) = surface_slopes(source, x, z, cell_size, intensity);
+            let normal = Vec3::new(-slope_x, 1.0, -slope_z).normalize();
+            // the tangent follows the u texture coordinate (+x), the bitangent (normal x tangent * w) the v coordinate (+z)
+            let tangent = Vec3::new(1.0, slope_x, 0.0).normalize();
+            normals.push(normal.to_array());
+            tangents.push([tangent.x, tangent.y, tangent.z, -1.0]);
+        }
+    }
+    ChunkGrid { columns, rows, heights, normals, tangents }
+}
+
+/// Returns the slopes along x and z in the real world at the world position (x, z),
+/// by central differences over one mesh cell of `cell_size` (x, z).
+fn surface_slopes(source: &dyn TerrainSource, x: f64, z: f64, cell_size: (f64, f64), intensity: f32) -> (f32, f32) {
+    let value = |x: f64, z: f64| source.height(x, z) as f32;
+    let slope_x = (value(x + cell_size.0, z) - value(x - cell_size.0, z)) * intensity / (2.0 * cell_size.0 as f32);
+    let slope_z = (value(x, z + cell_size.1) - value(x, z - cell_size.1)) * intensity / (2.0 * cell_size.1 as f32);
+    (slope_x, slope_z)
+}
+
+/// Returns the height of the terrain surface at the world position (x, z), scaled by `intensity`
+/// like the vertices of `create_mesh`. Between the vertices the source is interpolated (bilinearly for an `ImageSource`).
+pub fn surface_height(source: &dyn TerrainSource, x: f64, z: f64, intensity: f32) -> f32 {
+    source.height(x, z) as f32 * intensity
+}
+
+/// Returns the normal of the terrain surface at the world position (x, z), calculated like the vertex normals
+/// of `create_mesh` with mesh cells of `cell_size` (x, z).
+pub fn surface_normal(source: &dyn TerrainSource, x: f64, z: f64, cell_size: (f64, f64), intensity: f32) -> Vec3 {
+    let (slope_x, slope_z) = surface_slopes(source, x, z, cell_size, intensity);
+    Vec3::new(-slope_x, 1.0, -slope_z).normalize()
+}
+
+/// Returns the size (x, z) of one mesh cell in the real world.
+fn cell_size(extent: f64, mesh_size: (usize, usize)) -> (f64, f64) {
+    (extent / mesh_size.0 as f64, extent / mesh_size.1 as f64)
+}
+
 /// Creates a mesh based on the given parameters and returns a `Mesh` object.
 /// The `extent` parameter determines the size of the mesh in the real world.
-/// The `mesh_width` and `mesh_depth` parameters determine the resolution of the mesh.
-/// The `map` parameter is an `ElevationMap` object containing the elevation data.
+/// The `mesh_width` and `mesh_depth` parameters determine the resolution of the mesh,
+/// `mesh_pos` is the position of the mesh in cells.
+/// The `source` parameter is a `TerrainSource` providing the elevation data.
 /// The `intensity` parameter controls the vertical scaling of the mesh.
-pub fn create_mesh(extent: f64, mesh_pos: (isize, isize), mesh_size: (usize, usize), map: &ElevationMap, intensity: f32) -> Mesh {
-    let (umap_width, umap_depth) = map.size;
-    let (map_width, map_depth) = (umap_width as isize, umap_depth as isize);
+/// The `lod` parameter reduces the resolution of the mesh and adds skirts to its borders.
+/// The `uv_extent` parameter is the size (x, z) in the real world covered once by the texture (e.g. the colormap
+/// of the whole elevation map), the texture coordinates of the mesh are offset to its position within it.
+#[allow(clippy::too_many_arguments)]
+pub fn create_mesh(extent: f64, mesh_pos: (isize, isize), mesh_size: (usize, usize), source: &dyn TerrainSource, intensity: f32, lod: Lod, uv_extent: (f64, f64)) -> Mesh {
+    let grid = sample_heights(mesh_pos, mesh_size, lod.stride, cell_size(extent, mesh_size), source, intensity);
+    build_mesh(extent, mesh_pos, mesh_size, &grid, intensity, lod.skirt_depth, uv_extent)
+}
+
+/// Creates both the mesh and the matching heightfield collider of a terrain chunk.
+/// The parameters are the same as for `create_mesh`, the terrain source is only sampled once,
+/// so the collider always matches the visible surface (skirts are left out).
+/// The heightfield expects evenly spaced vertices, so `lod.stride` has to divide both sides of `mesh_size`.
+/// The heightfield is offset to the mesh position, so it can be attached to an entity at the origin.
+#[allow(clippy::too_many_arguments)]
+pub fn create_chunk(extent: f64, mesh_pos: (isize, isize), mesh_size: (usize, usize), source: &dyn TerrainSource, intensity: f32, lod: Lod, uv_extent: (f64, f64)) -> (Mesh, Collider) {
+    let grid = sample_heights(mesh_pos, mesh_size, lod.stride, cell_size(extent, mesh_size), source, intensity);
+    (
+        build_mesh(extent, mesh_pos, mesh_size, &grid, intensity, lod.skirt_depth, uv_extent),
+        build_collider(extent, mesh_pos, mesh_size, &grid, intensity),
+    )
+}
+
+fn build_collider(extent: f64, mesh_pos: (isize, isize), mesh_size: (usize, usize), grid: &ChunkGrid, intensity: f32) -> Collider {
     let (mesh_width, mesh_depth) = mesh_size;
     let (mesh_x, mesh_y) = mesh_pos;
-    let (umesh_x, umesh_y) = (
-        // Calculate the position in the elevation map, considering repetition
-        if mesh_x < 0 { (mesh_x % map_width) + map_width } else { mesh_x % map_width } as usize, 
-        if mesh_y < 0 { (mesh_y % map_depth) + map_depth } else { mesh_y % map_depth } as usize
+    let extent_f32 = extent as f32;
+
+    // Rapier expects the heights in column-major order, with rows along z and columns along x
+    let (rows, cols) = (grid.rows.len(), grid.columns.len());
+    let mut column_major: Vec<f32> = Vec::with_capacity(rows * cols);
+    for w in 0..cols {
+        for d in 0..rows {
+            column_major.push(grid.heights[d * cols + w]);
+        }
+    }
+    let heightfield = Collider::heightfield(column_major, rows, cols, Vec3::new(extent_f32, intensity, extent_f32));
+
+    // The heightfield is centered around its origin, move it below the mesh
+    let center = Vec3::new(
+        (mesh_x as f32 / mesh_width as f32 + 0.5) * extent_f32,
+        0.0,
+        (mesh_y as f32 / mesh_depth as f32 + 0.5) * extent_f32,
     );
+    Collider::compound(vec![(center, Quat::IDENTITY, heightfield)])
+}
 
-    let vertices_count: usize = (mesh_width + 1) * (mesh_depth + 1);
-    let triangle_count: usize = mesh_width * mesh_depth * 2 * 3;
+fn build_mesh(extent: f64, mesh_pos: (isize, isize), mesh_size: (usize, usize), grid: &ChunkGrid, intensity: f32, skirt_depth: f32, uv_extent: (f64, f64)) -> Mesh {
+    let (mesh_width, mesh_depth) = mesh_size;
+    let (mesh_x, mesh_y) = mesh_pos;
+    let (cols, rows) = (grid.columns.len(), grid.rows.len());
+
+    let vertices_count: usize = cols * rows;
+    let triangle_count: usize = (cols - 1) * (rows - 1) * 2 * 3;
 
     // Cast (specific types needed for 3d api's, like bevy's 3d engine)
-    let (mesh_width_u32, mesh_depth_u32) = (mesh_width as u32, mesh_depth as u32);
+    let (cols_u32, rows_u32) = (cols as u32, rows as u32);
     let (mesh_width_f32, mesh_depth_f32) = (mesh_width as f32, mesh_depth as f32);
     let extent_f32 = extent as f32;
+    // texture coordinates of the mesh position, within the area covered by the texture
+    let (cell_x, cell_z) = cell_size(extent, mesh_size);
+    let uv_offset = (
+        (mesh_x as f64 * cell_x).rem_euclid(uv_extent.0) / uv_extent.0,
+        (mesh_y as f64 * cell_z).rem_euclid(uv_extent.1) / uv_extent.1,
+    );
+    let uv_cell = ((cell_x / uv_extent.0) as f32, (cell_z / uv_extent.1) as f32);
 
     // Defining vertices.
     let mut positions: Vec<[f32; 3]> = Vec::with_capacity(vertices_count);
-    let mut normals: Vec<[f32; 3]> = Vec::with_capacity(vertices_count);
+    let mut normals: Vec<[f32; 3]> = grid.normals.clone();
+    let mut tangents: Vec<[f32; 4]> = grid.tangents.clone();
     let mut uvs: Vec<[f32; 2]> = Vec::with_capacity(vertices_count);
-    for d in 0..=mesh_depth {
-        for w in 0..=mesh_width {
-            // Calculate the position in the elevation map, considering repetition
-            // (again, because the mesh might overlap over the borders)
-            let map_x = (umesh_x + w) % umap_width;
-            let map_y = (umesh_y + d) % umap_depth;
-
+    for (r, d) in grid.rows.iter().enumerate() {
+        for (c, w) in grid.columns.iter().enumerate() {
             // Cast
-            let (w_f32, d_f32) = (w as f32, d as f32);
+            let (w_f32, d_f32) = (*w as f32, *d as f32);
 
             let pos = [
                 (mesh_x as f32 + w_f32) * (extent_f32 / mesh_width_f32),
-                (map.get_value(map_x, map_y) as f32) * intensity,
+                grid.heights[r * cols + c] * intensity,
                 (mesh_y as f32 + d_f32) * (extent_f32 / mesh_depth_f32),
             ];
             positions.push(pos);
-            normals.push([0.0, 1.0, 0.0]);
-            uvs.push([w_f32 / mesh_width_f32, d_f32 / mesh_depth_f32]);
+            uvs.push([uv_offset.0 as f32 + w_f32 * uv_cell.0, uv_offset.1 as f32 + d_f32 * uv_cell.1]);
         }
     }
 
     // Defining triangles.
     let mut triangles: Vec<u32> = Vec::with_capacity(triangle_count);
-    for d in 0..mesh_depth_u32 {
-        for w in 0..mesh_width_u32 {
+    for d in 0..rows_u32 - 1 {
+        for w in 0..cols_u32 - 1 {
             // First triangle
-            triangles.push((d * (mesh_width_u32 + 1)) + w);
-            triangles.push(((d + 1) * (mesh_width_u32 + 1)) + w);
-            triangles.push(((d + 1) * (mesh_width_u32 + 1)) + w + 1);
+            triangles.push((d * cols_u32) + w);
+            triangles.push(((d + 1) * cols_u32) + w);
+            triangles.push(((d + 1) * cols_u32) + w + 1);
             // Second triangle
-            triangles.push((d * (mesh_width_u32 + 1)) + w);
-            triangles.push(((d + 1) * (mesh_width_u32 + 1)) + w + 1);
-            triangles.push((d * (mesh_width_u32 + 1)) + w + 1);
+            triangles.push((d * cols_u32) + w);
+            triangles.push(((d + 1) * cols_u32) + w + 1);
+            triangles.push((d * cols_u32) + w + 1);
+        }
+    }
+
+    // Defining skirts: the border vertices are walked around the mesh (with the outside on the left),
+    // each of them gets a copy moved down by skirt_depth and each border segment two triangles facing outwards.
+    if skirt_depth > 0.0 {
+        let border: Vec<u32> = (0..cols_u32).rev()
+            .chain((1..rows_u32).map(|d| d * cols_u32))
+            .chain((1..cols_u32).map(|w| (rows_u32 - 1) * cols_u32 + w))
+            .chain((0..rows_u32 - 1).rev().map(|d| d * cols_u32 + cols_u32 - 1))
+            .collect();
+        let skirt_start = positions.len() as u32;
+        for &i in &border {
+            let [x, y, z] = positions[i as usize];
+            positions.push([x, y - skirt_depth, z]);
+            normals.push(normals[i as usize]);
+            tangents.push(tangents[i as usize]);
+            uvs.push(uvs[i as usize]);
+        }
+        for (s, segment) in border.windows(2).enumerate() {
+            let (top_a, top_b) = (segment[0], segment[1]);
+            let (bottom_a, bottom_b) = (skirt_start + s as u32, skirt_start + s as u32 + 1);
+            triangles.extend([top_a, bottom_a, bottom_b]);
+            triangles.extend([top_a, bottom_b, top_b]);
         }
     }
 
@@ -175,6 +698,283 @@ pub fn create_mesh(extent: f64, mesh_pos: (isize, isize), mesh_size: (usize, usi
     mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
     mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
     mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_TANGENT, tangents);
 
     mesh
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use bevy_rapier3d::prelude::*;
+    use crate::source::{ImageSource, PlaneSource};
+
+    /// Encodes an image as TIFF in memory.
+    fn encode_tiff<C: tiff::encoder::colortype::ColorType>(width: u32, height: u32, data: &[C::Inner]) -> Vec<u8>
+    where [C::Inner]: tiff::encoder::TiffValue {
+        let mut content = std::io::Cursor::new(Vec::new());
+        tiff::encoder::TiffEncoder::new(&mut content).unwrap().write_image::<C>(width, height, data).unwrap();
+        content.into_inner()
+    }
+
+    #[test]
+    fn loading_reports_errors() {
+        let dir = std::env::temp_dir().join(format!("terrain-load-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |file: &str| dir.join(file).to_string_lossy().into_owned();
+        assert!(matches!(load_elevation_map(&path("missing.png"), 1.0), Err(TerrainError::Io(_))));
+
+        std::fs::write(path("garbage.png"), b"not an image").unwrap();
+        assert!(matches!(load_elevation_map(&path("garbage.png"), 1.0), Err(TerrainError::Decode(_))));
+        std::fs::write(path("garbage.tif"), b"II*\0 not a tiff").unwrap();
+        assert!(matches!(load_elevation_map(&path("garbage.tif"), 1.0), Err(TerrainError::Decode(_))));
+
+        // color TIFFs can't be used as elevation data
+        let rgb = encode_tiff::<tiff::encoder::colortype::RGB8>(2, 2, &[0; 12]);
+        std::fs::write(path("color.tif"), rgb).unwrap();
+        assert!(matches!(load_elevation_map(&path("color.tif"), 1.0), Err(TerrainError::UnsupportedFormat(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn raw_maps_are_decoded_little_endian() {
+        // 0x8000 and 0x4000, little-endian
+        let r16 = [0x00, 0x80, 0x00, 0x40, 0xff, 0xff, 0x00, 0x00];
+        let map = decode_elevation_map(&r16, HeightmapFormat::RawR16, None, 2.0).unwrap();
+        assert_eq!(map.size(), (2, 2));
+        assert_eq!(map.values(), [1.0, 0.5, 65535.0 * 2.0 / 65536.0, 0.0]);
+
+        let r32: Vec<u8> = [0.5f32, 0.25, 1.0].iter().flat_map(|value| value.to_le_bytes()).collect();
+        let map = decode_elevation_map(&r32, HeightmapFormat::RawR32, Some((3, 1)), 4.0).unwrap();
+        assert_eq!((map.size(), map.values()), ((3, 1), [2.0, 1.0, 4.0].as_slice()));
+
+        // sizes which don't match the content, and empty files
+        let mismatch = |content: &[u8], format, size| matches!(decode_elevation_map(content, format, size, 1.0), Err(TerrainError::Decode(_)));
+        assert!(mismatch(&r16, HeightmapFormat::RawR16, Some((3, 1))));
+        assert!(mismatch(&r16[..6], HeightmapFormat::RawR16, None));
+        assert!(mismatch(&r32, HeightmapFormat::RawR32, None));
+        assert!(mismatch(&[], HeightmapFormat::RawR16, None));
+        assert!(mismatch(&[], HeightmapFormat::RawR32, Some((0, 0))));
+    }
+
+    #[test]
+    fn tiff_maps_keep_their_precision() {
+        use tiff::encoder::colortype::{Gray16, Gray32Float, Gray8};
+        let gray8 = decode_elevation_map(&encode_tiff::<Gray8>(2, 1, &[128, 64]), HeightmapFormat::Tiff, None, 2.0).unwrap();
+        assert_eq!((gray8.size(), gray8.values()), ((2, 1), [1.0, 0.5].as_slice()));
+        let gray16 = decode_elevation_map(&encode_tiff::<Gray16>(1, 2, &[0x8000, 1]), HeightmapFormat::Tiff, None, 2.0).unwrap();
+        assert_eq!((gray16.size(), gray16.values()), ((1, 2), [1.0, 2.0 / 65536.0].as_slice()));
+        let float = decode_elevation_map(&encode_tiff::<Gray32Float>(2, 2, &[0.0, 0.125, 0.5, 1.0]), HeightmapFormat::Tiff, None, 4.0).unwrap();
+        assert_eq!((float.size(), float.values()), ((2, 2), [0.0, 0.5, 2.0, 4.0].as_slice()));
+    }
+
+    #[test]
+    fn formats_are_detected_by_extension_or_header() {
+        let tiff = encode_tiff::<tiff::encoder::colortype::Gray8>(1, 1, &[0]);
+        assert_eq!(HeightmapFormat::detect("map.r16", &[]), HeightmapFormat::RawR16);
+        assert_eq!(HeightmapFormat::detect("map.RAW", &[]), HeightmapFormat::RawR16);
+        assert_eq!(HeightmapFormat::detect("map.r32", &[]), HeightmapFormat::RawR32);
+        assert_eq!(HeightmapFormat::detect("map.TIFF", &[]), HeightmapFormat::Tiff);
+        assert_eq!(HeightmapFormat::detect("map.elev", &tiff), HeightmapFormat::Tiff);
+        assert_eq!(HeightmapFormat::detect("map.elev", b"MM\0*"), HeightmapFormat::Tiff);
+        // only raw and TIFF extensions decide the format, other files are sniffed
+        assert_eq!(HeightmapFormat::detect("map.png", &tiff), HeightmapFormat::Tiff);
+        assert_eq!(HeightmapFormat::detect("map", b"\x89PNG"), HeightmapFormat::Image);
+        // the asset loader claims every extension which decides the format
+        for extension in ["r16", "raw", "r32", "tif", "tiff"] {
+            assert!(ElevationMapLoader::default().extensions().contains(&extension), "{extension}");
+        }
+    }
+
+    /// Drops a ball onto a sloped terrain chunk and checks that it comes to rest on top of it.
+    #[test]
+    fn ball_rests_on_chunk_collider() {
+        let (width, depth) = (8, 8);
+        let extent = 16.0;
+        let intensity = 2.0;
+        // a gentle slope towards +x, starting at height 1.0
+        let values = (0..width * depth).map(|i| 1.0 + (i % width) as f64 * 0.05).collect();
+        let map = ElevationMap::new_with_data(width, depth, values);
+
+        let mut app = App::new();
+        app.add_plugins((MinimalPlugins, TransformPlugin, HierarchyPlugin, AssetPlugin::default(), bevy::scene::ScenePlugin))
+            .init_asset::<Mesh>()
+            .insert_resource(RapierConfiguration {
+                timestep_mode: TimestepMode::Fixed { dt: 1.0 / 60.0, substeps: 1 },
+                ..RapierConfiguration::default()
+            })
+            .add_plugins(RapierPhysicsPlugin::<NoUserData>::default());
+
+        // chunk at [1][1], so the collider offset is exercised as well
+        let source = ImageSource::new(Arc::new(map), (extent / width as f64, extent / depth as f64));
+        let (_mesh, collider) = create_chunk(extent, (width as isize, depth as isize), (width, depth), &source, intensity, Lod { stride: 1, skirt_depth: 0.0 }, (extent, extent));
+        app.world.spawn((TransformBundle::default(), collider));
+
+        let radius = 0.5;
+        let (x, z) = (1.5 * extent as f32, 1.5 * extent as f32);
+        let ball = app.world
+            .spawn((
+                TransformBundle::from_transform(Transform::from_xyz(x, 20.0, z)),
+                RigidBody::Dynamic,
+                Collider::ball(radius),
+                LockedAxes::TRANSLATION_LOCKED_X | LockedAxes::TRANSLATION_LOCKED_Z,
+            ))
+            .id();
+
+        for _ in 0..600 {
+            app.update();
+        }
+
+        // texel at the center of the chunk is x = 4, so the ground height there is 1.2
+        let ground = (1.0 + 4.0 * 0.05) * intensity;
+        let translation = app.world.get::<Transform>(ball).unwrap().translation;
+        assert!((translation.y - (ground + radius)).abs() < 0.05, "ball at {translation}, ground at {ground}");
+    }
+
+    /// Chunks smaller than the texture get their part of it, beyond the texture it repeats.
+    #[test]
+    fn uvs_are_offset_into_the_texture() {
+        let source = PlaneSource::constant(0.0);
+        let lod = Lod { stride: 1, skirt_depth: 0.0 };
+        // chunks of 10 world units with 4 cells, the texture covers 2x4 chunks
+        let uvs = |x: isize, y: isize| {
+            let mesh = create_mesh(10.0, (x * 4, y * 4), (4, 4), &source, 1.0, lod, (20.0, 40.0));
+            let uvs = match mesh.attribute(Mesh::ATTRIBUTE_UV_0) {
+                Some(bevy::render::mesh::VertexAttributeValues::Float32x2(uvs)) => uvs.clone(),
+                _ => panic!("mesh has no texture coordinates"),
+            };
+            (uvs[0], uvs[uvs.len() - 1])
+        };
+        assert_eq!(uvs(0, 0), ([0.0, 0.0], [0.5, 0.25]));
+        assert_eq!(uvs(1, 3), ([0.5, 0.75], [1.0, 1.0]));
+        assert_eq!(uvs(2, 4), uvs(0, 0));
+        assert_eq!(uvs(-1, -1), uvs(1, 3));
+    }
+
+    /// Heights and normals of the surface match the vertices of the mesh.
+    #[test]
+    fn surface_matches_mesh_vertices() {
+        let map = synthetic_map(EdgeMode::Mirror);
+        let source = ImageSource::new(Arc::new(map), (0.5, 0.5));
+        let (extent, mesh_size, intensity) = (2.0, (4, 4), 3.0);
+        let lod = Lod { stride: 1, skirt_depth: 0.0 };
+        let mesh = create_mesh(extent, (-4, 4), mesh_size, &source, intensity, lod, (extent, extent));
+        let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap();
+        let normals = mesh.attribute(Mesh::ATTRIBUTE_NORMAL).unwrap().as_float3().unwrap();
+        for (position, normal) in positions.iter().zip(normals) {
+            let (x, z) = (position[0] as f64, position[2] as f64);
+            assert!((surface_height(&source, x, z, intensity) - position[1]).abs() < 1e-5);
+            assert!(surface_normal(&source, x, z, (0.5, 0.5), intensity).abs_diff_eq(Vec3::from(*normal), 1e-5));
+        }
+        // between the texels the heights are interpolated bilinearly
+        let corners = [(0.0, 2.0), (0.5, 2.0), (0.0, 2.5), (0.5, 2.5)].map(|(x, z)| surface_height(&source, x, z, intensity));
+        let center = surface_height(&source, 0.25, 2.25, intensity);
+        assert!((center - corners.iter().sum::<f32>() / 4.0).abs() < 1e-5);
+    }
+
+    const EDGE_MODES: [EdgeMode; 4] = [EdgeMode::Wrap, EdgeMode::Clamp, EdgeMode::Mirror, EdgeMode::SeaLevel(-1.0)];
+
+    /// Small map with distinct values, which aren't seamless at the borders.
+    fn synthetic_map(edge_mode: EdgeMode) -> ElevationMap {
+        let (width, depth) = (5, 4);
+        let values = (0..width * depth).map(|i| ((i * 7) % 11) as f64 * 0.25).collect();
+        ElevationMap::new_with_data(width, depth, values).with_edge_mode(edge_mode)
+    }
+
+    #[test]
+    fn edge_modes_resolve_outside_values() {
+        let map = synthetic_map(EdgeMode::Wrap);
+        assert_eq!(map.get_value(-1, 0), map.get_value(4, 0));
+        assert_eq!(map.get_value(5, 4), map.get_value(0, 0));
+
+        let map = synthetic_map(EdgeMode::Clamp);
+        assert_eq!(map.get_value(-3, 1), map.get_value(0, 1));
+        assert_eq!(map.get_value(7, 9), map.get_value(4, 3));
+
+        let map = synthetic_map(EdgeMode::Mirror);
+        assert_eq!(map.get_value(-1, 0), map.get_value(1, 0));
+        assert_eq!(map.get_value(5, 0), map.get_value(3, 0));
+        assert_eq!(map.get_value(8, 0), map.get_value(0, 0));
+        assert_eq!(map.get_value(2, -2), map.get_value(2, 2));
+
+        let map = synthetic_map(EdgeMode::SeaLevel(-1.0));
+        assert_eq!(map.get_value(-1, 0), -1.0);
+        assert_eq!(map.get_value(2, 4), -1.0);
+        assert_eq!(map.get_value(4, 3), map.map[19]);
+    }
+
+    #[test]
+    fn lod_offsets_include_both_borders() {
+        assert_eq!(lod_offsets(8, 1), (0..=8).collect::<Vec<_>>());
+        assert_eq!(lod_offsets(16, 4), [0, 4, 8, 12, 16]);
+        assert_eq!(lod_offsets(4, 16), [0, 4]);
+        assert_eq!(lod_offsets(8, 0), lod_offsets(8, 1));
+        // a stride which doesn't divide the cells still reaches the far border, but unevenly
+        let offsets = lod_offsets(100, 16);
+        assert_eq!((offsets.len(), offsets.first(), offsets.last()), (8, Some(&0), Some(&100)));
+        assert!(offsets.windows(2).any(|pair| pair[1] - pair[0] != offsets[1]));
+    }
+
+    #[test]
+    fn skirts_hang_below_the_border_vertices() {
+        let source = PlaneSource::constant(1.0);
+        let (size, lod) = ((8, 4), Lod { stride: 2, skirt_depth: 3.0 });
+        let plain = create_mesh(8.0, (0, 0), size, &source, 2.0, Lod { skirt_depth: 0.0, ..lod }, (8.0, 8.0));
+        let skirted = create_mesh(8.0, (0, 0), size, &source, 2.0, lod, (8.0, 8.0));
+        // 5 x 3 vertices, 12 of them on the border, walked around in a closed loop of 12 segments
+        let (vertices, segments) = (plain.count_vertices(), 12);
+        assert_eq!(vertices, 15);
+        assert_eq!(skirted.count_vertices(), vertices + segments + 1);
+        // two triangles per border segment
+        assert_eq!(skirted.indices().unwrap().len(), plain.indices().unwrap().len() + segments * 2 * 3);
+
+        let positions = skirted.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap();
+        for skirt in &positions[vertices..] {
+            assert_eq!(skirt[1], 2.0 - 3.0);
+            let [x, _, z] = *skirt;
+            assert!(x == 0.0 || x == 8.0 || z == 0.0 || z == 8.0, "skirt vertex {skirt:?} isn't on the border");
+            assert!(positions[..vertices].iter().any(|top| top[0] == x && top[2] == z && top[1] == 2.0));
+        }
+    }
+
+    /// Returns the positions and normals of the vertices in the given column or row of a full resolution mesh.
+    fn border_vertices(mesh: &Mesh, size: (usize, usize), column: Option<usize>, row: Option<usize>) -> Vec<([f32; 3], [f32; 3])> {
+        let positions = mesh.attribute(Mesh::ATTRIBUTE_POSITION).unwrap().as_float3().unwrap();
+        let normals = mesh.attribute(Mesh::ATTRIBUTE_NORMAL).unwrap().as_float3().unwrap();
+        let (cols, rows) = (size.0 + 1, size.1 + 1);
+        (0..rows).flat_map(|r| (0..cols).map(move |c| (r, c)))
+            .filter(|(r, c)| column.is_none_or(|column| *c == column) && row.is_none_or(|row| *r == row))
+            .map(|(r, c)| (positions[r * cols + c], normals[r * cols + c]))
+            .collect()
+    }
+
+    #[test]
+    fn neighbouring_tiles_share_edge_vertices() {
+        for edge_mode in EDGE_MODES {
+            let map = synthetic_map(edge_mode);
+            let size = map.size();
+            let (width, depth) = (size.0 as isize, size.1 as isize);
+            let source = ImageSource::new(Arc::new(map), (10.0 / size.0 as f64, 10.0 / size.1 as f64));
+            let lod = Lod { stride: 1, skirt_depth: 0.0 };
+            let tile = |x: isize, y: isize| create_mesh(10.0, (x * width, y * depth), size, &source, 2.0, lod, (10.0, 10.0));
+
+            for x in -1..=1 {
+                let (left, right) = (tile(x - 1, 0), tile(x, 0));
+                assert_eq!(
+                    border_vertices(&left, size, Some(size.0), None),
+                    border_vertices(&right, size, Some(0), None),
+                    "{edge_mode:?}: tiles [{}][0] and [{x}][0] don't match", x - 1
+                );
+            }
+            for y in -1..=1 {
+                let (top, bottom) = (tile(0, y - 1), tile(0, y));
+                assert_eq!(
+                    border_vertices(&top, size, None, Some(size.1)),
+                    border_vertices(&bottom, size, None, Some(0)),
+                    "{edge_mode:?}: tiles [0][{}] and [0][{y}] don't match", y - 1
+                );
+            }
+        }
+    }
+}
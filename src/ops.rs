@@ -0,0 +1,262 @@
+//! Operations for composing elevation maps, e.g.
+//! `base.add_map(&detail.scale(0.2)).terrace(0.5, 0.2).blur(1.0).normalize(0.0, 4.0)`.
+//! Every operation consumes the map and returns the result, so they can be chained.
+//! Operations with a second map sample it stretched over the whole first map, so the sizes don't have to match.
+use crate::mesh::ElevationMap;
+
+impl ElevationMap {
+    /// Applies `f` to every elevation value.
+    pub fn map_values(mut self, f: impl Fn(f64) -> f64) -> Self {
+        self.values_mut().iter_mut().for_each(|value| *value = f(*value));
+        self
+    }
+
+    /// Combines every elevation value with the value of `other` at the same relative position.
+    pub fn zip_with(mut self, other: &ElevationMap, f: impl Fn(f64, f64) -> f64) -> Self {
+        let (width, height) = self.size();
+        let same_size = other.size() == (width, height);
+        let scale_x = (other.size().0 as f64 - 1.0) / (width as f64 - 1.0).max(1.0);
+        let scale_y = (other.size().1 as f64 - 1.0) / (height as f64 - 1.0).max(1.0);
+        for (i, value) in self.values_mut().iter_mut().enumerate() {
+            let other_value = if same_size {
+                other.values()[i]
+            } else {
+                other.sample((i % width) as f64 * scale_x, (i / width) as f64 * scale_y)
+            };
+            *value = f(*value, other_value);
+        }
+        self
+    }
+
+    /// Adds the elevation values of `other`.
+    pub fn add_map(self, other: &ElevationMap) -> Self {
+        self.zip_with(other, |a, b| a + b)
+    }
+
+    /// Multiplies by the elevation values of `other`.
+    pub fn multiply_map(self, other: &ElevationMap) -> Self {
+        self.zip_with(other, |a, b| a * b)
+    }
+
+    /// Adds `offset` to every elevation value.
+    pub fn offset(self, offset: f64) -> Self {
+        self.map_values(|value| value + offset)
+    }
+
+    /// Multiplies every elevation value by `factor`.
+    pub fn scale(self, factor: f64) -> Self {
+        self.map_values(|value| value * factor)
+    }
+
+    /// Interpolates linearly towards `other`, where the `mask` is 1.0 (values are clamped to 0.0 to 1.0).
+    pub fn lerp(self, other: &ElevationMap, mask: &ElevationMap) -> Self {
+        let (width, height) = self.size();
+        let mask = mask.clone().resample(width, height);
+        let other = other.clone().resample(width, height);
+        let blended = other.zip_with(&mask, |b, t| b * t.clamp(0.0, 1.0));
+        self.zip_with(&mask, |a, t| a * (1.0 - t.clamp(0.0, 1.0))).add_map(&blended)
+    }
+
+    /// Takes the lower of both elevation values.
+    pub fn min(self, other: &ElevationMap) -> Self {
+        self.zip_with(other, f64::min)
+    }
+
+    /// Takes the higher of both elevation values.
+    pub fn max(self, other: &ElevationMap) -> Self {
+        self.zip_with(other, f64::max)
+    }
+
+    /// Limits the elevation values to the range `min` to `max`.
+    pub fn clamp(self, min: f64, max: f64) -> Self {
+        self.map_values(|value| value.clamp(min, max))
+    }
+
+    /// Returns the lowest and highest elevation value.
+    pub fn range(&self) -> (f64, f64) {
+        self.values().iter().fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), &value| (min.min(value), max.max(value)))
+    }
+
+    /// Stretches the elevation values linearly to the range `min` to `max`.
+    /// A flat map is set to `min`.
+    pub fn normalize(self, min: f64, max: f64) -> Self {
+        let (low, high) = self.range();
+        let factor = if high > low { (max - min) / (high - low) } else { 0.0 };
+        self.map_values(|value| min + (value - low) * factor)
+    }
+
+    /// Remaps the elevation values by a piecewise linear curve of (input, output) points, sorted by input.
+    /// Values outside of the curve get the output of the first or last point.
+    pub fn remap(self, curve: &[(f64, f64)]) -> Self {
+        assert!(!curve.is_empty(), "the remap curve needs at least one point");
+        self.map_values(|value| {
+            let next = curve.iter().position(|(input, _)| *input > value);
+            match next {
+                Some(0) => curve[0].1,
+                None => curve[curve.len() - 1].1,
+                Some(i) => {
+                    let ((x0, y0), (x1, y1)) = (curve[i - 1], curve[i]);
+                    y0 + (y1 - y0) * (value - x0) / (x1 - x0)
+                },
+            }
+        })
+    }
+
+    /// Turns the terrain into terraces of `step` height.
+    /// With a `smoothness` of 0.0 the terraces have vertical walls, with 1.0 they are plain slopes again.
+    pub fn terrace(self, step: f64, smoothness: f64) -> Self {
+        let smoothness = smoothness.clamp(0.0, 1.0);
+        self.map_values(|value| {
+            let level = (value / step).floor();
+            let t = value / step - level;
+            let t = if smoothness > 0.0 { ((t - (1.0 - smoothness)) / smoothness).clamp(0.0, 1.0) } else { 0.0 };
+            (level + t) * step
+        })
+    }
+
+    /// Mirrors the elevation values below `level` upwards, giving valleys instead of sharp ridges.
+    pub fn abs(self, level: f64) -> Self {
+        self.map_values(|value| (value - level).abs())
+    }
+
+    /// Mirrors the elevation values above `level` downwards, turning the crossings of `level` into sharp ridges.
+    pub fn ridge(self, level: f64) -> Self {
+        self.map_values(|value| level - (value - level).abs())
+    }
+
+    /// Turns the terrain upside down, keeping the range of the elevation values.
+    pub fn invert(self) -> Self {
+        let (low, high) = self.range();
+        self.map_values(|value| high + low - value)
+    }
+
+    /// Blurs the terrain with a gaussian kernel of standard deviation `sigma` (in texels).
+    /// Values outside of the map are sampled according to the edge mode.
+    pub fn blur(self, sigma: f64) -> Self {
+        if sigma <= 0.0 {
+            return self;
+        }
+        let radius = (sigma * 3.0).ceil() as isize;
+        let kernel: Vec<f64> = (-radius..=radius).map(|i| (-((i * i) as f64) / (2.0 * sigma * sigma)).exp()).collect();
+        let total: f64 = kernel.iter().sum();
+        let kernel: Vec<f64> = kernel.iter().map(|weight| weight / total).collect();
+        // separable: first horizontally, then vertically
+        let blur_pass = |map: ElevationMap, (dx, dy): (isize, isize)| {
+            let (width, height) = map.size();
+            let values = (0..width * height)
+                .map(|i| {
+                    let (x, y) = ((i % width) as isize, (i / width) as isize);
+                    kernel.iter().zip(-radius..=radius).map(|(weight, k)| weight * map.get_value(x + k * dx, y + k * dy)).sum()
+                })
+                .collect();
+            let edge_mode = map.edge_mode();
+            ElevationMap::new_with_data(width, height, values).with_edge_mode(edge_mode)
+        };
+        blur_pass(blur_pass(self, (1, 0)), (0, 1))
+    }
+
+    /// Resamples the map to a new resolution with bilinear interpolation, the corners stay in place.
+    pub fn resample(self, width: usize, height: usize) -> Self {
+        if self.size() == (width, height) {
+            return self;
+        }
+        let (old_width, old_height) = self.size();
+        let scale_x = (old_width as f64 - 1.0) / (width as f64 - 1.0).max(1.0);
+        let scale_y = (old_height as f64 - 1.0) / (height as f64 - 1.0).max(1.0);
+        let values = (0..width * height)
+            .map(|i| self.sample((i % width) as f64 * scale_x, (i / width) as f64 * scale_y))
+            .collect();
+        ElevationMap::new_with_data(width, height, values).with_edge_mode(self.edge_mode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::EdgeMode;
+
+    fn map(width: usize, values: &[f64]) -> ElevationMap {
+        ElevationMap::new_with_data(width, values.len() / width, values.to_vec())
+    }
+
+    fn assert_values(map: &ElevationMap, expected: &[f64]) {
+        assert_eq!(map.values().len(), expected.len());
+        for (value, expected) in map.values().iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-9, "{:?} != {expected:?}", map.values());
+        }
+    }
+
+    #[test]
+    fn arithmetic_operations() {
+        let a = map(2, &[1.0, 2.0, 3.0, 4.0]);
+        let b = map(2, &[4.0, 3.0, 2.0, 1.0]);
+        assert_values(&a.clone().add_map(&b), &[5.0, 5.0, 5.0, 5.0]);
+        assert_values(&a.clone().multiply_map(&b), &[4.0, 6.0, 6.0, 4.0]);
+        assert_values(&a.clone().min(&b), &[1.0, 2.0, 2.0, 1.0]);
+        assert_values(&a.clone().max(&b), &[4.0, 3.0, 3.0, 4.0]);
+        assert_values(&a.clone().offset(1.0).scale(2.0), &[4.0, 6.0, 8.0, 10.0]);
+        assert_values(&a.clone().clamp(1.5, 3.5), &[1.5, 2.0, 3.0, 3.5]);
+        // a smaller map is stretched over the whole map
+        assert_values(&a.add_map(&map(1, &[1.0])), &[2.0, 3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn lerp_with_mask() {
+        let a = map(2, &[0.0, 0.0, 0.0, 0.0]);
+        let b = map(2, &[4.0, 4.0, 4.0, 4.0]);
+        let mask = map(2, &[0.0, 0.25, 1.0, 2.0]);
+        assert_values(&a.lerp(&b, &mask), &[0.0, 1.0, 4.0, 4.0]);
+    }
+
+    #[test]
+    fn normalize_invert_and_range() {
+        let a = map(2, &[1.0, 2.0, 3.0, 5.0]);
+        assert_eq!(a.range(), (1.0, 5.0));
+        assert_values(&a.clone().normalize(0.0, 1.0), &[0.0, 0.25, 0.5, 1.0]);
+        assert_values(&a.invert(), &[5.0, 4.0, 3.0, 1.0]);
+        assert_values(&map(2, &[2.0; 4]).normalize(-1.0, 1.0), &[-1.0; 4]);
+    }
+
+    #[test]
+    fn remap_by_curve() {
+        let a = map(5, &[-1.0, 0.0, 0.5, 1.0, 2.0]);
+        let curve = [(0.0, 0.0), (0.5, 0.8), (1.0, 1.0)];
+        assert_values(&a.remap(&curve), &[0.0, 0.0, 0.8, 1.0, 1.0]);
+        let b = map(2, &[0.25, 0.75]);
+        assert_values(&b.remap(&curve), &[0.4, 0.9]);
+    }
+
+    #[test]
+    fn terrace_ridge_and_abs() {
+        let a = map(4, &[0.2, 0.9, 1.5, 2.95]);
+        assert_values(&a.clone().terrace(1.0, 0.0), &[0.0, 0.0, 1.0, 2.0]);
+        assert_values(&a.clone().terrace(1.0, 1.0), &[0.2, 0.9, 1.5, 2.95]);
+        assert_values(&a.clone().terrace(1.0, 0.2), &[0.0, 0.5, 1.0, 2.75]);
+        assert_values(&a.clone().abs(1.0), &[0.8, 0.1, 0.5, 1.95]);
+        assert_values(&a.ridge(1.0), &[0.2, 0.9, 0.5, -0.95]);
+    }
+
+    #[test]
+    fn blur_spreads_spikes_and_keeps_flat_maps() {
+        let mut spike = ElevationMap::new(7, 7).with_edge_mode(EdgeMode::SeaLevel(0.0));
+        spike._set_value(3, 3, 1.0);
+        let blurred = spike.blur(1.0);
+        // the kernel is normalized, so no material is lost within the map
+        assert!((blurred.values().iter().sum::<f64>() - 1.0).abs() < 1e-3);
+        assert!(blurred.get_value(3, 3) < 0.2);
+        assert!(blurred.get_value(2, 3) > 0.0);
+        assert!((blurred.get_value(2, 3) - blurred.get_value(3, 4)).abs() < 1e-12);
+        let flat = map(3, &[2.0; 9]).with_edge_mode(EdgeMode::Clamp).blur(2.0);
+        assert_values(&flat, &[2.0; 9]);
+    }
+
+    #[test]
+    fn resample_keeps_corners() {
+        let a = map(2, &[0.0, 1.0, 2.0, 3.0]);
+        let resampled = a.resample(3, 3);
+        assert_eq!(resampled.size(), (3, 3));
+        assert_values(&resampled, &[0.0, 0.5, 1.0, 1.0, 1.5, 2.0, 2.0, 2.5, 3.0]);
+        let back = resampled.resample(2, 2);
+        assert_values(&back, &[0.0, 1.0, 2.0, 3.0]);
+    }
+}
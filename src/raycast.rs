@@ -0,0 +1,176 @@
+use bevy::math::DVec3;
+use bevy::prelude::Vec3;
+use crate::chunk::{ChunkCoord, ChunkLayout};
+use crate::mesh::Heightmap;
+
+/// Where a ray hits the terrain surface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainHit {
+    /// hit point in world space
+    pub point: Vec3,
+    /// normal of the surface at the hit point
+    pub normal: Vec3,
+    /// distance from the origin of the ray to the hit point
+    pub distance: f32,
+    /// texel (x, z) at the lowest corner of the texel cell which was hit
+    pub texel: (isize, isize),
+    /// chunk containing the hit point
+    pub chunk: ChunkCoord,
+}
+
+/// Casts a ray against the surface of the elevation map (or another `Heightmap`), laid out in the world by `layout` and scaled by `intensity`
+/// like the terrain meshes. The texel cells below the ray are traversed in order (Amanatides & Woo), and within each cell
+/// the ray is intersected with the bilinear surface between its four texels, like the terrain source samples it.
+/// Beyond the map the edge mode of the map applies. A ray starting below the surface hits at its origin.
+/// Returns None if the ray doesn't hit the surface within `max_distance`.
+pub fn raycast<M: Heightmap + ?Sized>(map: &M, layout: &ChunkLayout, intensity: f32, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<TerrainHit> {
+    let direction = direction.try_normalize()?.as_dvec3();
+    let origin = origin.as_dvec3();
+    let (intensity, max_distance) = (intensity as f64, max_distance as f64);
+    // position and direction of the ray in texels (x, z)
+    let (start_x, start_z) = layout.world_to_texel(origin.x, origin.z);
+    let (dir_x, dir_z) = (direction.x * layout.texels_per_unit, direction.z * layout.texels_per_unit);
+
+    // distance to the first cell border and between two cell borders along each axis
+    let (mut cell_x, mut cell_z) = (start_x.floor() as isize, start_z.floor() as isize);
+    let axis = |start: f64, cell: isize, dir: f64| -> (isize, f64, f64) {
+        if dir > 0.0 {
+            (1, ((cell + 1) as f64 - start) / dir, 1.0 / dir)
+        } else if dir < 0.0 {
+            (-1, (cell as f64 - start) / dir, -1.0 / dir)
+        } else {
+            (0, f64::INFINITY, f64::INFINITY)
+        }
+    };
+    let (step_x, mut next_x, delta_x) = axis(start_x, cell_x, dir_x);
+    let (step_z, mut next_z, delta_z) = axis(start_z, cell_z, dir_z);
+
+    let mut enter = 0.0;
+    while enter <= max_distance {
+        let exit = next_x.min(next_z).min(max_distance);
+        let corners = [(0, 0), (1, 0), (0, 1), (1, 1)].map(|(dx, dz)| map.get_value(cell_x + dx, cell_z + dz) * intensity);
+        // position within the cell (0.0 to 1.0) along the ray
+        let uv = |t: f64| (start_x + dir_x * t - cell_x as f64, start_z + dir_z * t - cell_z as f64);
+        let above = |t: f64| {
+            let (u, v) = uv(t);
+            origin.y + direction.y * t - bilinear(corners, u, v)
+        };
+        if let Some(t) = first_root(above, enter, exit) {
+            let point = origin + direction * t;
+            let (u, v) = uv(t);
+            // slopes of the bilinear surface in the real world
+            let slope_x = ((corners[1] - corners[0]) * (1.0 - v) + (corners[3] - corners[2]) * v) * layout.texels_per_unit;
+            let slope_z = ((corners[2] - corners[0]) * (1.0 - u) + (corners[3] - corners[1]) * u) * layout.texels_per_unit;
+            return Some(TerrainHit {
+                point: point.as_vec3(),
+                normal: DVec3::new(-slope_x, 1.0, -slope_z).normalize().as_vec3(),
+                distance: t as f32,
+                texel: (cell_x, cell_z),
+                chunk: layout.world_to_chunk(point.x, point.z),
+            });
+        }
+        if exit >= max_distance {
+            break;
+        }
+        if next_x < next_z {
+            cell_x += step_x;
+            next_x += delta_x;
+        } else {
+            cell_z += step_z;
+            next_z += delta_z;
+        }
+        enter = exit;
+    }
+    None
+}
+
+/// Returns the bilinearly interpolated value of the corners [(0, 0), (1, 0), (0, 1), (1, 1)] at (u, v).
+fn bilinear(corners: [f64; 4], u: f64, v: f64) -> f64 {
+    let top = corners[0] + (corners[1] - corners[0]) * u;
+    let bottom = corners[2] + (corners[3] - corners[2]) * u;
+    top + (bottom - top) * v
+}
+
+/// Returns the first t from `start` to `end` where the height above the surface reaches 0.0.
+/// Along a ray through one cell of a bilinear surface the height is a quadratic function of t,
+/// so it is fitted through three samples and solved.
+fn first_root(height: impl Fn(f64) -> f64, start: f64, end: f64) -> Option<f64> {
+    let (h0, h1, h2) = (height(start), height((start + end) * 0.5), height(end));
+    if h0 <= 0.0 {
+        return Some(start);
+    }
+    let length = end - start;
+    if length <= 0.0 {
+        return None;
+    }
+    // h(s) = a * s^2 + b * s + h0 with s = t - start
+    let a = 2.0 * (h2 - 2.0 * h1 + h0) / (length * length);
+    let b = (4.0 * h1 - 3.0 * h0 - h2) / length;
+    let roots = if a.abs() < 1e-12 {
+        [-h0 / b, f64::NAN]
+    } else {
+        let discriminant = b * b - 4.0 * a * h0;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let root = discriminant.sqrt();
+        [(-b - root) / (2.0 * a), (-b + root) / (2.0 * a)]
+    };
+    roots.into_iter()
+        .filter(|s| (0.0..=length).contains(s))
+        .min_by(f64::total_cmp)
+        .map(|s| start + s)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::{EdgeMode, ElevationMap};
+
+    // texels of 2 world units, chunks of 20 world units
+    const LAYOUT: ChunkLayout = ChunkLayout { chunk_size: 20.0, resolution: 10, texels_per_unit: 0.5 };
+
+    #[test]
+    fn rays_hit_flat_terrain_on_both_sides_of_the_origin() {
+        let map = ElevationMap::new_with_data(8, 8, vec![1.0; 64]);
+        for (x, z) in [(5.0, 5.0), (-25.0, -3.0), (13.0, -41.0)] {
+            let hit = raycast(&map, &LAYOUT, 2.0, Vec3::new(x, 10.0, z), Vec3::NEG_Y, 100.0).unwrap();
+            assert!(hit.point.abs_diff_eq(Vec3::new(x, 2.0, z), 1e-5), "{hit:?}");
+            assert!(hit.normal.abs_diff_eq(Vec3::Y, 1e-6));
+            assert!((hit.distance - 8.0).abs() < 1e-5);
+            assert_eq!(hit.texel, LAYOUT.world_to_texel_index(x as f64, z as f64));
+            assert_eq!(hit.chunk, LAYOUT.world_to_chunk(x as f64, z as f64));
+        }
+        let hit = raycast(&map, &LAYOUT, 2.0, Vec3::new(-25.0, 10.0, -3.0), Vec3::NEG_Y, 100.0).unwrap();
+        assert_eq!((hit.texel, hit.chunk), ((-13, -2), ChunkCoord(-2, -1)));
+
+        // rays going up, too short or starting below the surface
+        assert!(raycast(&map, &LAYOUT, 2.0, Vec3::new(5.0, 10.0, 5.0), Vec3::new(1.0, 0.1, 0.0), 100.0).is_none());
+        assert!(raycast(&map, &LAYOUT, 2.0, Vec3::new(5.0, 10.0, 5.0), Vec3::NEG_Y, 7.0).is_none());
+        let hit = raycast(&map, &LAYOUT, 2.0, Vec3::new(5.0, 1.0, 5.0), Vec3::X, 100.0).unwrap();
+        assert_eq!(hit.distance, 0.0);
+    }
+
+    #[test]
+    fn rays_hit_the_bilinear_surface() {
+        // a single peak of 5.0 at texel (10, 10) on flat ground
+        let mut map = ElevationMap::new(32, 32).with_edge_mode(EdgeMode::Clamp);
+        map._set_value(10, 10, 5.0);
+        let surface = |point: Vec3| map.sample(point.x as f64 * 0.5, point.z as f64 * 0.5) as f32;
+
+        // a horizontal ray at half the height of the peak crossing many cells
+        let hit = raycast(&map, &LAYOUT, 1.0, Vec3::new(-30.0, 2.5, 20.0), Vec3::X, 100.0).unwrap();
+        assert!((hit.point.x - 19.0).abs() < 1e-4 && hit.point.y == 2.5, "{hit:?}");
+        assert_eq!(hit.texel, (9, 10));
+        assert!(hit.normal.x < 0.0 && hit.normal.y > 0.0);
+        // the same ray in the other direction, and a slanted ray
+        let hit = raycast(&map, &LAYOUT, 1.0, Vec3::new(60.0, 2.5, 20.0), Vec3::NEG_X, 100.0).unwrap();
+        assert!((hit.point.x - 21.0).abs() < 1e-4 && hit.texel == (10, 10) && hit.normal.x > 0.0, "{hit:?}");
+        let hit = raycast(&map, &LAYOUT, 1.0, Vec3::new(0.0, 30.0, 0.0), Vec3::new(20.5, -28.0, 20.3), 100.0).unwrap();
+        assert!((hit.point.y - surface(hit.point)).abs() < 1e-4, "{hit:?}");
+        assert!(hit.point.y > 0.0);
+        // a ray passing just beside the peak misses it and hits the ground
+        let hit = raycast(&map, &LAYOUT, 1.0, Vec3::new(-30.0, 2.5, 24.0), Vec3::new(1.0, -0.01, 0.0), 1000.0).unwrap();
+        assert!(hit.point.y.abs() < 1e-4 && hit.point.x > 150.0, "{hit:?}");
+    }
+}
@@ -0,0 +1,323 @@
+use std::path::Path;
+use std::sync::Arc;
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::Velocity;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use rust_bevy_fun::delta::MapDelta;
+use rust_bevy_fun::mesh::ElevationMap;
+use crate::helper::{SimpleTween, VelocityTween};
+use crate::MovableBall;
+use crate::Terrain;
+use crate::TerrainMesh;
+use crate::WorldSeed;
+
+/// Saves the world with F5 and loads it again with F9.
+/// The save file contains the edited terrain and the state of the ball, see `SaveFile`.
+pub struct SavePlugin;
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, (save_world, load_world.before(crate::map_update)));
+    }
+}
+
+/// Save file of the world, stored as JSON.
+/// Every change of the format increases `SaveFile::VERSION` and adds a migration to `MIGRATIONS`,
+/// so older save files keep loading.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct SaveFile {
+    format: String,
+    version: u32,
+    seed: u64,
+    terrain: TerrainSave,
+    ball: BallSave,
+}
+impl SaveFile {
+    const FORMAT: &'static str = "rust-bevy-fun world";
+    const VERSION: u32 = 1;
+    const PATH: &'static str = "saves/world.json";
+    /// size of the tiles of terrain deltas
+    const DELTA_TILE_SIZE: usize = 32;
+}
+
+/// Migrations of save files, `MIGRATIONS[i]` converts a save file of version `i + 1` to version `i + 2`.
+/// (E.g. renaming a field: `save["ball"]["speed"] = save["ball"]["velocity"].take()`)
+const MIGRATIONS: &[Migration] = &[];
+const _: () = assert!(MIGRATIONS.len() + 1 == SaveFile::VERSION as usize, "every version needs a migration from the version before");
+
+/// Converts a save file to the next version
+type Migration = fn(&mut Value) -> Result<(), String>;
+
+/// Terrain of a save file
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+enum TerrainSave {
+    /// the terrain isn't edited (or generated from the seed)
+    Unchanged,
+    /// the changed tiles of the loaded elevation map
+    Delta(MapDelta),
+    /// the whole elevation map
+    Map(ElevationMap),
+}
+
+/// Position, rotation and movement of the ball
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct BallSave {
+    translation: Vec3,
+    rotation: Quat,
+    linvel: Vec3,
+    angvel: Vec3,
+    velocity: VelocityTween,
+    orbit_speed: SimpleTween,
+}
+
+/// Errors of loading or saving the world
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    Json(serde_json::Error),
+    /// the file isn't a save file of this game, or of a newer version
+    Format(String),
+    /// the terrain doesn't fit to the loaded elevation map
+    Terrain(String),
+}
+
+impl std::fmt::Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Io(err) => write!(f, "couldn't access save file: {err}"),
+            SaveError::Json(err) => write!(f, "invalid save file: {err}"),
+            SaveError::Format(err) => write!(f, "unsupported save file: {err}"),
+            SaveError::Terrain(err) => write!(f, "saved terrain doesn't fit: {err}"),
+        }
+    }
+}
+
+impl From<std::io::Error> for SaveError {
+    fn from(err: std::io::Error) -> Self {
+        SaveError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for SaveError {
+    fn from(err: serde_json::Error) -> Self {
+        SaveError::Json(err)
+    }
+}
+
+/// Returns the elevation map of the saved terrain, edited from the `base` map of the terrain,
+/// or None if the terrain isn't based on an elevation map.
+fn saved_map(terrain: TerrainSave, base: Option<&Arc<ElevationMap>>) -> Result<Option<Arc<ElevationMap>>, SaveError> {
+    match (terrain, base) {
+        (TerrainSave::Unchanged, base) => Ok(base.cloned()),
+        (TerrainSave::Delta(delta), Some(base)) => delta.apply(base).map(|map| Some(Arc::new(map))).map_err(SaveError::Terrain),
+        (TerrainSave::Delta(_), None) => Err(SaveError::Terrain("no elevation map is loaded".to_string())),
+        // the map isn't validated when it is deserialized
+        (TerrainSave::Map(map), _) => {
+            let (width, height) = map.size();
+            if width == 0 || height == 0 || width.checked_mul(height) != Some(map.values().len()) {
+                return Err(SaveError::Terrain(format!("the map has {} values instead of {width}x{height}", map.values().len())));
+            }
+            Ok(Some(Arc::new(map)))
+        },
+    }
+}
+
+/// Brings a save file of an older version up to the `current` version (usually `SaveFile::VERSION`) with the given migrations.
+fn migrate(save: &mut Value, migrations: &[Migration], current: u32) -> Result<(), SaveError> {
+    if save["format"] != SaveFile::FORMAT {
+        return Err(SaveError::Format(format!("not a {} save file", SaveFile::FORMAT)));
+    }
+    let current = current as u64;
+    let mut version = save["version"].as_u64().ok_or_else(|| SaveError::Format("missing version".to_string()))?;
+    if version == 0 || version > current {
+        return Err(SaveError::Format(format!("version {version} isn't supported, up to version {current}")));
+    }
+    while version < current {
+        let migration = migrations.get(version as usize - 1)
+            .ok_or_else(|| SaveError::Format(format!("no migration from version {version}")))?;
+        migration(save).map_err(|err| SaveError::Format(format!("migration from version {version}: {err}")))?;
+        version += 1;
+        save["version"] = version.into();
+    }
+    Ok(())
+}
+
+fn read_save_file(path: &Path) -> Result<SaveFile, SaveError> {
+    let mut save: Value = serde_json::from_slice(&std::fs::read(path)?)?;
+    migrate(&mut save, MIGRATIONS, SaveFile::VERSION)?;
+    Ok(serde_json::from_value(save)?)
+}
+
+fn write_save_file(path: &Path, save: &SaveFile) -> Result<(), SaveError> {
+    if let Some(directory) = path.parent() {
+        std::fs::create_dir_all(directory)?;
+    }
+    std::fs::write(path, serde_json::to_vec(save)?)?;
+    Ok(())
+}
+
+/// Save the edited terrain and the ball into `SaveFile::PATH` when F5 is pressed
+fn save_world(
+    input: Res<Input<KeyCode>>,
+    terrain: Res<Terrain>,
+    seed: Res<WorldSeed>,
+    ball_query: Query<(&Transform, &Velocity, &MovableBall)>,
+) {
+    if !input.just_pressed(KeyCode::F5) {
+        return;
+    }
+    let terrain_save = match (&terrain.base_map, &terrain.map) {
+        (_, None) => TerrainSave::Unchanged,
+        (Some(base), Some(map)) if Arc::ptr_eq(base, map) => TerrainSave::Unchanged,
+        (Some(base), Some(map)) => match MapDelta::between(base, map, SaveFile::DELTA_TILE_SIZE) {
+            Some(delta) => TerrainSave::Delta(delta),
+            None => TerrainSave::Map(map.as_ref().clone()),
+        },
+        (None, Some(map)) => TerrainSave::Map(map.as_ref().clone()),
+    };
+    let (transform, velocity, ball) = ball_query.single();
+    let save = SaveFile {
+        format: SaveFile::FORMAT.to_string(),
+        version: SaveFile::VERSION,
+        seed: seed.0,
+        terrain: terrain_save,
+        ball: BallSave {
+            translation: transform.translation,
+            rotation: transform.rotation,
+            linvel: velocity.linvel,
+            angvel: velocity.angvel,
+            velocity: ball.velocity.clone(),
+            orbit_speed: ball.orbit_speed.clone(),
+        },
+    };
+    match write_save_file(Path::new(SaveFile::PATH), &save) {
+        Ok(()) => println!("World saved to {}", SaveFile::PATH),
+        Err(err) => eprintln!("Saving the world failed: {err}"),
+    }
+}
+
+/// Load the terrain and the ball from `SaveFile::PATH` when F9 is pressed
+fn load_world(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain: ResMut<Terrain>,
+    input: Res<Input<KeyCode>>,
+    seed: Res<WorldSeed>,
+    mut ball_query: Query<(&mut Transform, &mut Velocity, &mut MovableBall)>,
+    chunk_query: Query<(Entity, &TerrainMesh)>,
+) {
+    if !input.just_pressed(KeyCode::F9) {
+        return;
+    }
+    let save = match read_save_file(Path::new(SaveFile::PATH)) {
+        Ok(save) => save,
+        Err(err) => {
+            eprintln!("Loading the world failed: {err}");
+            return;
+        },
+    };
+    if save.seed != seed.0 {
+        eprintln!("The world was saved with seed {}, start with --seed {} to get the same world", save.seed, save.seed);
+    }
+    let map = match saved_map(save.terrain, terrain.base_map.as_ref()) {
+        Ok(map) => map,
+        Err(err) => {
+            eprintln!("Loading the world failed: {err}");
+            return;
+        },
+    };
+    if let Some(map) = map {
+        terrain.set_map(map);
+        terrain.history.clear();
+        terrain.clear_cache(&mut meshes);
+        for (entity, chunk) in &chunk_query {
+            commands.entity(entity).insert(terrain.spawn_chunk_task(chunk.coord, chunk.lod));
+        }
+    }
+
+    let (mut transform, mut velocity, mut ball) = ball_query.single_mut();
+    transform.translation = save.ball.translation;
+    transform.rotation = save.ball.rotation;
+    velocity.linvel = save.ball.linvel;
+    velocity.angvel = save.ball.angvel;
+    ball.velocity = save.ball.velocity;
+    ball.orbit_speed = save.ball.orbit_speed;
+    println!("World loaded from {}", SaveFile::PATH);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn save_file() -> SaveFile {
+        let base = ElevationMap::new(40, 40);
+        let mut edited = base.clone();
+        edited._set_value(35, 2, 1.5);
+        SaveFile {
+            format: SaveFile::FORMAT.to_string(),
+            version: SaveFile::VERSION,
+            seed: 42,
+            terrain: TerrainSave::Delta(MapDelta::between(&base, &edited, SaveFile::DELTA_TILE_SIZE).unwrap()),
+            ball: BallSave {
+                translation: Vec3::new(1.0, 2.0, 3.0),
+                rotation: Quat::from_rotation_y(0.5),
+                linvel: Vec3::X,
+                angvel: Vec3::ZERO,
+                velocity: VelocityTween::new(Vec3::X, 0.0, 24.0, 20.0),
+                orbit_speed: SimpleTween::new(60.0, 50.0, 300.0, 5.0),
+            },
+        }
+    }
+
+    #[test]
+    fn save_file_round_trips() {
+        let path = std::env::temp_dir().join(format!("world-save-{}.json", std::process::id()));
+        let save = save_file();
+        write_save_file(&path, &save).unwrap();
+        assert_eq!(read_save_file(&path).unwrap(), save);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn saved_maps_are_validated() {
+        let map = ElevationMap::new(4, 3);
+        let saved = |map: &ElevationMap| serde_json::from_value::<TerrainSave>(serde_json::to_value(TerrainSave::Map(map.clone())).unwrap()).unwrap();
+        assert_eq!(saved_map(saved(&map), None).unwrap().as_deref(), Some(&map));
+
+        // a truncated map, and one without values
+        let mut truncated = serde_json::to_value(TerrainSave::Map(map)).unwrap();
+        truncated["map"].as_array_mut().unwrap().pop();
+        let truncated = serde_json::from_value::<TerrainSave>(truncated).unwrap();
+        assert!(matches!(saved_map(truncated, None), Err(SaveError::Terrain(_))));
+        assert!(matches!(saved_map(saved(&ElevationMap::new(0, 3)), None), Err(SaveError::Terrain(_))));
+    }
+
+    #[test]
+    fn older_versions_are_migrated() {
+        // a version 1 save file, whose ball had a single "speed" value instead of the orbit speed tween
+        let mut save = serde_json::to_value(save_file()).unwrap();
+        let orbit_speed = save["ball"]["orbit_speed"].take();
+        save["ball"].as_object_mut().unwrap().remove("orbit_speed");
+        save["ball"]["speed"] = orbit_speed["cur"].clone();
+        let migrations: &[Migration] = &[|save| {
+            let speed = save["ball"]["speed"].as_f64().ok_or("missing speed")?;
+            save["ball"]["orbit_speed"] = serde_json::json!({ "cur": speed, "min": 50.0, "max": 300.0, "inc": 5.0 });
+            Ok(())
+        }];
+        migrate(&mut save, migrations, 2).unwrap();
+        assert_eq!(save["version"], 2);
+        save["version"] = SaveFile::VERSION.into();
+        assert_eq!(serde_json::from_value::<SaveFile>(save).unwrap(), save_file());
+
+        let mut newer = serde_json::to_value(save_file()).unwrap();
+        newer["version"] = (SaveFile::VERSION + 1).into();
+        assert!(matches!(migrate(&mut newer, MIGRATIONS, SaveFile::VERSION), Err(SaveError::Format(_))));
+        let mut other = serde_json::json!({ "format": "something else", "version": 1 });
+        assert!(matches!(migrate(&mut other, MIGRATIONS, SaveFile::VERSION), Err(SaveError::Format(_))));
+        // a version without a migration to it
+        let mut older = serde_json::to_value(save_file()).unwrap();
+        assert!(matches!(migrate(&mut older, MIGRATIONS, SaveFile::VERSION + 1), Err(SaveError::Format(_))));
+    }
+}
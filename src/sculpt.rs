@@ -0,0 +1,215 @@
+use std::sync::Arc;
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use bevy_egui::egui;
+use rust_bevy_fun::brush::{Brush, BrushKind};
+use rust_bevy_fun::chunk::ChunkCoord;
+use rust_bevy_fun::mesh::ElevationMap;
+use crate::cursor::{update_cursor_hit, CursorHit};
+use crate::{DirtyChunk, PendingChunk};
+use crate::Terrain;
+use crate::TerrainMesh;
+use crate::WorldSeed;
+
+/// Edit mode for sculpting the terrain with the mouse, toggled with E.
+/// Brushes change the elevation map, and the chunks showing the changed texels are rebuilt.
+/// Every brush stroke can be undone with Ctrl+Z and redone with Ctrl+Y.
+pub struct SculptPlugin;
+
+impl Plugin for SculptPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<SculptState>()
+            .add_systems(Update, (sculpt_ui_system, (sculpt_terrain.after(update_cursor_hit), undo_redo).before(crate::map_update)));
+    }
+}
+
+#[derive(Resource)]
+pub struct SculptState {
+    pub enabled: bool,
+    pub kind: BrushKind,
+    /// radius in world units
+    pub radius: f32,
+    /// see `Brush::strength`
+    pub strength: f32,
+    /// see `Brush::falloff`
+    pub falloff: f32,
+}
+impl SculptState {
+    const KEYS: [KeyCode; 5] = [KeyCode::Key1, KeyCode::Key2, KeyCode::Key3, KeyCode::Key4, KeyCode::Key5];
+}
+impl Default for SculptState {
+    fn default() -> Self {
+        SculptState {
+            enabled: false,
+            kind: BrushKind::default(),
+            radius: 4.0,
+            strength: 1.0,
+            falloff: 0.5,
+        }
+    }
+}
+
+fn sculpt_ui_system(
+    mut contexts: EguiContexts,
+    input: Res<Input<KeyCode>>,
+    mut state: ResMut<SculptState>,
+) {
+    if input.just_pressed(KeyCode::E) {
+        state.enabled = !state.enabled;
+    }
+    for (key, kind) in SculptState::KEYS.iter().zip(BrushKind::ALL) {
+        if input.just_pressed(*key) {
+            state.kind = kind;
+        }
+    }
+    if !state.enabled {
+        return;
+    }
+    egui::Window::new("Sculpting").show(contexts.ctx_mut(), |ui| {
+        ui.label("left MB to sculpt, Ctrl+Z/Ctrl+Y to undo/redo, E to leave the edit mode");
+        ui.horizontal(|ui| {
+            for (i, kind) in BrushKind::ALL.into_iter().enumerate() {
+                ui.selectable_value(&mut state.kind, kind, format!("{}:{kind:?}", i + 1));
+            }
+        });
+        ui.add(egui::Slider::new(&mut state.radius, 0.5..=50.0).text("radius"));
+        ui.add(egui::Slider::new(&mut state.strength, 0.01..=10.0).logarithmic(true).text("strength"));
+        ui.add(egui::Slider::new(&mut state.falloff, 0.0..=1.0).text("falloff"));
+    });
+}
+
+/// Apply the brush where the mouse cursor hits the terrain, while the left mouse button is pressed.
+/// The changes are recorded in the edit history, a stroke ends when the button is released.
+#[allow(clippy::too_many_arguments)]
+fn sculpt_terrain(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain: ResMut<Terrain>,
+    state: Res<SculptState>,
+    seed: Res<WorldSeed>,
+    time: Res<Time>,
+    buttons: Res<Input<MouseButton>>,
+    cursor_hit: Res<CursorHit>,
+    chunk_query: Query<(Entity, &TerrainMesh, Has<PendingChunk>)>,
+) {
+    let terrain = terrain.as_mut();
+    let Some(map) = &terrain.map else { return };
+    if buttons.just_released(MouseButton::Left) {
+        terrain.history.commit(map);
+    }
+    if !state.enabled || !buttons.pressed(MouseButton::Left) || contexts.ctx_mut().wants_pointer_input() {
+        return;
+    }
+    let Some(hit) = cursor_hit.0 else { return };
+
+    // brush position and radius in texels of the elevation map
+    let layout = terrain.layout();
+    let brush = Brush {
+        kind: state.kind,
+        radius: state.radius as f64 * layout.texels_per_unit,
+        strength: state.strength as f64,
+        falloff: state.falloff as f64,
+        seed: seed.noise_seed(),
+    };
+    let center = layout.world_to_texel(hit.point.x as f64, hit.point.z as f64);
+    let Some(mut map) = take_map(terrain) else { return };
+    let changes = brush.apply(Arc::make_mut(&mut map), center, time.delta_seconds() as f64);
+    terrain.history.record(&changes);
+    let changed: Vec<usize> = changes.iter().map(|(index, _)| *index).collect();
+    rebuild_changed_chunks(&mut commands, &mut meshes, terrain, map, &changed, &chunk_query);
+}
+
+/// Undo the last terrain edit with Ctrl+Z, redo it with Ctrl+Y (or Ctrl+Shift+Z)
+fn undo_redo(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut terrain: ResMut<Terrain>,
+    input: Res<Input<KeyCode>>,
+    chunk_query: Query<(Entity, &TerrainMesh, Has<PendingChunk>)>,
+) {
+    if !input.any_pressed([KeyCode::ControlLeft, KeyCode::ControlRight]) {
+        return;
+    }
+    let shift = input.any_pressed([KeyCode::ShiftLeft, KeyCode::ShiftRight]);
+    let (undo, redo) = (input.just_pressed(KeyCode::Z) && !shift, input.just_pressed(KeyCode::Y) || (input.just_pressed(KeyCode::Z) && shift));
+    if !undo && !redo {
+        return;
+    }
+    let terrain = terrain.as_mut();
+    let Some(mut map) = take_map(terrain) else { return };
+    let edit = if undo {
+        terrain.history.undo(Arc::make_mut(&mut map))
+    } else {
+        terrain.history.redo(Arc::make_mut(&mut map))
+    };
+    let changed = edit.map_or_else(Vec::new, |edit| edit.indices().collect::<Vec<usize>>());
+    if !changed.is_empty() {
+        println!("{} terrain edit of {} texels", if undo { "Undoing" } else { "Redoing" }, changed.len());
+    }
+    rebuild_changed_chunks(&mut commands, &mut meshes, terrain, map, &changed, &chunk_query);
+}
+
+/// Takes the elevation map out of the terrain (and its terrain source), so it can be edited in place:
+/// chunk tasks only get copies of their texels, so the map is only cloned while the unedited base map shares it.
+/// It is put back by `rebuild_changed_chunks`.
+fn take_map(terrain: &mut Terrain) -> Option<Arc<ElevationMap>> {
+    let map = terrain.map.take()?;
+    terrain.source = None;
+    Some(map)
+}
+
+/// Use the edited elevation map and rebuild the chunks containing a changed texel
+/// (or its neighbours, which share the normals), if any.
+/// Chunks which are still being generated are rebuilt once their task has finished, see `DirtyChunk`.
+fn rebuild_changed_chunks(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    terrain: &mut Terrain,
+    map: Arc<ElevationMap>,
+    changed: &[usize],
+    chunk_query: &Query<(Entity, &TerrainMesh, Has<PendingChunk>)>,
+) {
+    if changed.is_empty() {
+        terrain.set_map(map);
+        return;
+    }
+    let (width, depth) = map.size();
+    let changed: Vec<(usize, usize)> = changed.iter().map(|index| (index % width, index / width)).collect();
+    let layout = terrain.layout();
+    let is_changed = |coord: ChunkCoord| {
+        // the columns and rows of the map shown by the chunk, according to the edge mode
+        let (columns, rows) = layout.chunk_texels(coord);
+        let (mut chunk_columns, mut chunk_rows) = (vec![false; width], vec![false; depth]);
+        for (x, _) in columns.filter_map(|x| map.resolve(x, 0)) {
+            chunk_columns[x] = true;
+        }
+        for (_, y) in rows.filter_map(|y| map.resolve(0, y)) {
+            chunk_rows[y] = true;
+        }
+        changed.iter().any(|(x, y)| chunk_columns[*x] && chunk_rows[*y])
+    };
+    let affected: Vec<(Entity, _, _, bool)> = chunk_query.iter()
+        .filter(|(_, chunk, _)| is_changed(chunk.coord))
+        .map(|(entity, chunk, pending)| (entity, chunk.coord, chunk.lod, pending))
+        .collect();
+    // cached chunks showing a changed texel are outdated, the other ones are kept
+    let outdated: Vec<ChunkCoord> = terrain.chunk_cache.as_ref()
+        .map_or_else(Vec::new, |cache| cache.keys().copied().filter(|coord| is_changed(*coord)).collect());
+
+    terrain.set_map(map);
+    if let Some(cache) = terrain.chunk_cache.as_mut() {
+        for chunk in outdated.iter().filter_map(|coord| cache.take(coord)) {
+            meshes.remove(&chunk.mesh);
+        }
+    }
+    for (entity, coord, lod, pending) in affected {
+        if pending {
+            // replacing the task would cancel it, and the chunk wouldn't change before the stroke ends
+            commands.entity(entity).insert(DirtyChunk);
+        } else {
+            commands.entity(entity).insert(terrain.spawn_chunk_task(coord, lod));
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::sync::Arc;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin};
+use crate::mesh::{ElevationMap, Heightmap};
+
+/// A source of terrain elevation, which can be sampled at any world-space position (x, z).
+/// The returned height is scaled by the terrain intensity when creating meshes.
+pub trait TerrainSource: Send + Sync {
+    fn height(&self, x: f64, z: f64) -> f64;
+}
+
+/// Terrain from an `ElevationMap` (or another `Heightmap`), where each texel covers `texel_size` (x, z) world units.
+/// Between the texels the elevation is interpolated bilinearly, outside of the map
+/// it depends on the edge mode of the map.
+pub struct ImageSource<M: Heightmap + ?Sized = ElevationMap> {
+    map: Arc<M>,
+    texel_size: (f64, f64),
+    origin: (isize, isize),
+}
+
+impl<M: Heightmap + ?Sized> ImageSource<M> {
+    pub fn new(map: Arc<M>, texel_size: (f64, f64)) -> Self {
+        Self { map, texel_size, origin: (0, 0) }
+    }
+
+    /// Places the first texel of the map at the texel position `origin` (x, z), e.g. for a `Heightmap::region`.
+    pub fn with_origin(mut self, origin: (isize, isize)) -> Self {
+        self.origin = origin;
+        self
+    }
+}
+
+impl<M: Heightmap + ?Sized> TerrainSource for ImageSource<M> {
+    fn height(&self, x: f64, z: f64) -> f64 {
+        self.map.sample(x / self.texel_size.0 - self.origin.0 as f64, z / self.texel_size.1 - self.origin.1 as f64)
+    }
+}
+
+/// Terrain from fractal brownian motion noise, configured like `generate_noisemap`.
+/// World positions are multiplied by `scale` before sampling the noise, the noise values
+/// (from -1.0 to 1.0) are mapped to heights from 0.0 to `max_height`.
+pub struct NoiseSource {
+    fbm: Fbm<Perlin>,
+    scale: f64,
+    max_height: f64,
+}
+
+impl NoiseSource {
+    pub fn new(seed: u32, frequency: f64, lacunarity: f64, octaves: usize, scale: f64, max_height: f64) -> Self {
+        let fbm = Fbm::<Perlin>::new(seed)
+            .set_frequency(frequency)
+            .set_lacunarity(lacunarity)
+            .set_octaves(octaves);
+        Self { fbm, scale, max_height }
+    }
+}
+
+impl TerrainSource for NoiseSource {
+    fn height(&self, x: f64, z: f64) -> f64 {
+        let value = self.fbm.get([x * self.scale, z * self.scale]);
+        (value.clamp(-1.0, 1.0) + 1.0) * 0.5 * self.max_height
+    }
+}
+
+/// Planar terrain with the given height at the origin, rising by `gradient` (x, z) per world unit.
+pub struct PlaneSource {
+    height: f64,
+    gradient: (f64, f64),
+}
+
+impl PlaneSource {
+    pub fn new(height: f64, gradient: (f64, f64)) -> Self {
+        Self { height, gradient }
+    }
+
+    /// Flat terrain with a constant height.
+    pub fn constant(height: f64) -> Self {
+        Self::new(height, (0.0, 0.0))
+    }
+}
+
+impl TerrainSource for PlaneSource {
+    fn height(&self, x: f64, z: f64) -> f64 {
+        self.height + self.gradient.0 * x + self.gradient.1 * z
+    }
+}
+
+/// Endless terrain from seeded, domain-warped fractal noise, which never repeats.
+/// The gradients of the noise lattice are hashed from the seed and the (64 bit) lattice coordinates,
+/// unlike the 256 entry permutation table of the noise crate, which repeats every 256 lattice cells.
+/// The same seed always gives the same heights, so chunks can be regenerated at any time.
+pub struct WorldSource {
+    seed: u64,
+    /// world units per lattice cell of the first octave
+    feature_size: f64,
+    octaves: usize,
+    lacunarity: f64,
+    persistence: f64,
+    /// maximum displacement of the sample positions by the warp noise, in world units
+    warp_strength: f64,
+    max_height: f64,
+}
+
+impl WorldSource {
+    const WARP_SEED_X: u64 = 0x5851_f42d_4c95_7f2d;
+    const WARP_SEED_Z: u64 = 0x1405_7b7e_f767_814f;
+
+    pub fn new(seed: u64, feature_size: f64, max_height: f64) -> Self {
+        Self {
+            seed,
+            feature_size,
+            octaves: 6,
+            // (not exactly 2.0, so the lattices of the octaves never line up)
+            lacunarity: 2.03,
+            persistence: 0.5,
+            warp_strength: feature_size * 0.5,
+            max_height,
+        }
+    }
+
+    /// Fractal brownian motion of gradient noise, in the range -1.0 to 1.0
+    fn fbm(&self, seed: u64, x: f64, z: f64) -> f64 {
+        let (mut x, mut z) = (x / self.feature_size, z / self.feature_size);
+        let (mut amplitude, mut sum, mut total) = (1.0, 0.0, 0.0);
+        for octave in 0..self.octaves {
+            sum += gradient_noise(seed.wrapping_add(octave as u64), x, z) * amplitude;
+            total += amplitude;
+            amplitude *= self.persistence;
+            x *= self.lacunarity;
+            z *= self.lacunarity;
+        }
+        sum / total
+    }
+}
+
+impl TerrainSource for WorldSource {
+    fn height(&self, x: f64, z: f64) -> f64 {
+        let warp_x = self.fbm(self.seed ^ Self::WARP_SEED_X, x, z) * self.warp_strength;
+        let warp_z = self.fbm(self.seed ^ Self::WARP_SEED_Z, x, z) * self.warp_strength;
+        let value = self.fbm(self.seed, x + warp_x, z + warp_z);
+        (value.clamp(-1.0, 1.0) + 1.0) * 0.5 * self.max_height
+    }
+}
+
+/// Mixes the seed and lattice coordinates into a pseudo-random value (splitmix64 finalizer)
+fn lattice_hash(seed: u64, x: i64, z: i64) -> u64 {
+    let mut hash = seed
+        ^ (x as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15)
+        ^ (z as u64).wrapping_mul(0xc2b2_ae3d_27d4_eb4f);
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+/// Gradient noise on an unbounded lattice, continuous and roughly in the range -1.0 to 1.0
+fn gradient_noise(seed: u64, x: f64, z: f64) -> f64 {
+    let (x0, z0) = (x.floor(), z.floor());
+    let (fx, fz) = (x - x0, z - z0);
+    let (ix, iz) = (x0 as i64, z0 as i64);
+    let corner = |dx: i64, dz: i64| {
+        let angle = (lattice_hash(seed, ix + dx, iz + dz) >> 11) as f64 / (1u64 << 53) as f64 * std::f64::consts::TAU;
+        angle.cos() * (fx - dx as f64) + angle.sin() * (fz - dz as f64)
+    };
+    // quintic fade curve, so the noise is smooth across lattice cells
+    let fade = |t: f64| t * t * t * (t * (t * 6.0 - 15.0) + 10.0);
+    let (u, v) = (fade(fx), fade(fz));
+    let top = corner(0, 0) + (corner(1, 0) - corner(0, 0)) * u;
+    let bottom = corner(0, 1) + (corner(1, 1) - corner(0, 1)) * u;
+    // 2D gradient noise is within +-sqrt(0.5)
+    (top + (bottom - top) * v) * std::f64::consts::SQRT_2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn region_sources_match_the_whole_map() {
+        let map = ElevationMap::new_with_data(8, 6, (0..48).map(|i| ((i * 7) % 11) as f64).collect());
+        let whole = ImageSource::new(Arc::new(map.clone()), (0.5, 0.5));
+        // texels -3 to 4 and 2 to 9, wrapped around the borders of the map
+        let region = ImageSource::new(Arc::new(map.region(-3..=4, 2..=9)), (0.5, 0.5)).with_origin((-3, 2));
+        for (x, z) in [(-1.5, 1.0), (-0.3, 3.7), (1.99, 4.49), (0.25, 1.25)] {
+            assert!((region.height(x, z) - whole.height(x, z)).abs() < 1e-9, "({x}, {z})");
+        }
+    }
+
+    #[test]
+    fn world_source_is_deterministic_per_seed() {
+        let positions = [(0.0, 0.0), (-1234.5, 87.25), (1.0e7, -3.0e6)];
+        let heights = |source: &WorldSource| positions.map(|(x, z)| source.height(x, z));
+        assert_eq!(heights(&WorldSource::new(7, 200.0, 4.0)), heights(&WorldSource::new(7, 200.0, 4.0)));
+        assert_ne!(heights(&WorldSource::new(7, 200.0, 4.0)), heights(&WorldSource::new(8, 200.0, 4.0)));
+    }
+
+    #[test]
+    fn world_source_is_continuous_and_does_not_repeat() {
+        let source = WorldSource::new(42, 200.0, 4.0);
+        for i in -50..50 {
+            let (x, z) = (i as f64 * 37.3, i as f64 * -11.9);
+            let height = source.height(x, z);
+            assert!((0.0..=4.0).contains(&height));
+            assert!((height - source.height(x + 0.01, z)).abs() < 0.01);
+            assert!((height - source.height(x, z + 0.01)).abs() < 0.01);
+            // the lattice of the noise crate repeats after 256 cells
+            assert_ne!(height, source.height(x + 256.0 * 200.0, z));
+        }
+    }
+}
@@ -0,0 +1,451 @@
+//! Tiled on-disk elevation maps, for maps too large to be kept in memory as an `ElevationMap`.
+//!
+//! File layout (all numbers little-endian):
+//! - header of `TiledHeader::SIZE` bytes: magic `TILEDHM\0`, format version (u32),
+//!   width, height and tile size in texels (u32 each), sample format (u8), edge mode (u8), 2 bytes padding,
+//!   sea level, min height and max height (f64 each)
+//! - tile index: the file offset (u64) of every tile, row by row
+//! - tiles: the samples of every tile row by row, f32 values or u16 values scaled from min to max height.
+//!   Tiles at the right and bottom border of the map are cut off at the border.
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use crate::mesh::{EdgeMode, ElevationMap, Heightmap, TerrainError};
+
+/// How the elevation values of a tiled elevation map are stored.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SampleFormat {
+    /// 32-bit floats, 4 bytes per texel
+    #[default]
+    F32,
+    /// 16-bit values scaled between the min and max height of the map, 2 bytes per texel
+    U16,
+}
+
+impl SampleFormat {
+    fn bytes(self) -> usize {
+        match self {
+            SampleFormat::F32 => 4,
+            SampleFormat::U16 => 2,
+        }
+    }
+}
+
+/// Header of a tiled elevation map file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TiledHeader {
+    pub size: (usize, usize),
+    pub tile_size: usize,
+    pub format: SampleFormat,
+    pub edge_mode: EdgeMode,
+    /// range of the elevation values, used for scaling u16 samples
+    pub height_range: (f64, f64),
+}
+
+impl TiledHeader {
+    const MAGIC: &'static [u8; 8] = b"TILEDHM\0";
+    const VERSION: u32 = 1;
+    const SIZE: usize = 52;
+
+    /// Returns the number of tiles (x, y).
+    pub fn tiles(&self) -> (usize, usize) {
+        (self.size.0.div_ceil(self.tile_size), self.size.1.div_ceil(self.tile_size))
+    }
+
+    /// Returns the size (width, height) of the tile at (x, y), which is smaller at the borders of the map.
+    fn tile_extent(&self, tile_x: usize, tile_y: usize) -> (usize, usize) {
+        (
+            self.tile_size.min(self.size.0 - tile_x * self.tile_size),
+            self.tile_size.min(self.size.1 - tile_y * self.tile_size),
+        )
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        let (edge_mode, sea_level) = match self.edge_mode {
+            EdgeMode::Wrap => (0u8, 0.0),
+            EdgeMode::Clamp => (1, 0.0),
+            EdgeMode::Mirror => (2, 0.0),
+            EdgeMode::SeaLevel(level) => (3, level),
+        };
+        let format = match self.format {
+            SampleFormat::F32 => 0u8,
+            SampleFormat::U16 => 1,
+        };
+        let mut bytes = Vec::with_capacity(Self::SIZE);
+        bytes.extend_from_slice(Self::MAGIC);
+        bytes.extend_from_slice(&Self::VERSION.to_le_bytes());
+        for value in [self.size.0, self.size.1, self.tile_size] {
+            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+        }
+        bytes.extend_from_slice(&[format, edge_mode, 0, 0]);
+        for value in [sea_level, self.height_range.0, self.height_range.1] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8; Self::SIZE]) -> Result<Self, TerrainError> {
+        let invalid = |message: String| TerrainError::Decode(message.into());
+        if &bytes[0..8] != Self::MAGIC {
+            return Err(invalid("not a tiled elevation map".to_string()));
+        }
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let f64_at = |i: usize| f64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        let version = u32_at(8);
+        if version != Self::VERSION {
+            return Err(invalid(format!("tiled elevation map version {version} isn't supported")));
+        }
+        let (width, height, tile_size) = (u32_at(12) as usize, u32_at(16) as usize, u32_at(20) as usize);
+        if width == 0 || height == 0 || tile_size == 0 {
+            return Err(invalid(format!("invalid size {width}x{height} with tiles of {tile_size}")));
+        }
+        let format = match bytes[24] {
+            0 => SampleFormat::F32,
+            1 => SampleFormat::U16,
+            format => return Err(invalid(format!("unknown sample format {format}"))),
+        };
+        let edge_mode = match bytes[25] {
+            0 => EdgeMode::Wrap,
+            1 => EdgeMode::Clamp,
+            2 => EdgeMode::Mirror,
+            3 => EdgeMode::SeaLevel(f64_at(28)),
+            mode => return Err(invalid(format!("unknown edge mode {mode}"))),
+        };
+        Ok(Self { size: (width, height), tile_size, format, edge_mode, height_range: (f64_at(36), f64_at(44)) })
+    }
+}
+
+/// Writes the map (e.g. an `ElevationMap`) as tiled elevation map with tiles of `tile_size` texels.
+/// The map is read tile by tile, so it can be another tiled elevation map as well.
+pub fn write_tiled_elevation_map(map: &(impl Heightmap + ?Sized), path: impl AsRef<Path>, tile_size: usize, format: SampleFormat) -> io::Result<()> {
+    let (width, height) = map.size();
+    if tile_size == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the tile size must not be 0"));
+    }
+    let height_range = match format {
+        SampleFormat::F32 => (0.0, 0.0),
+        SampleFormat::U16 => (0..height).flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| map.value(x, y))
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), value| (min.min(value), max.max(value))),
+    };
+    let header = TiledHeader { size: (width, height), tile_size, format, edge_mode: map.edge_mode(), height_range };
+    let (tiles_x, tiles_y) = header.tiles();
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(&header.to_bytes())?;
+    let mut offset = (TiledHeader::SIZE + tiles_x * tiles_y * 8) as u64;
+    for tile_y in 0..tiles_y {
+        for tile_x in 0..tiles_x {
+            writer.write_all(&offset.to_le_bytes())?;
+            let (tile_width, tile_height) = header.tile_extent(tile_x, tile_y);
+            offset += (tile_width * tile_height * format.bytes()) as u64;
+        }
+    }
+    let (min, max) = height_range;
+    let scale = if max > min { 65535.0 / (max - min) } else { 0.0 };
+    for tile_y in 0..tiles_y {
+        for tile_x in 0..tiles_x {
+            let (tile_width, tile_height) = header.tile_extent(tile_x, tile_y);
+            for y in tile_y * tile_size..tile_y * tile_size + tile_height {
+                for x in tile_x * tile_size..tile_x * tile_size + tile_width {
+                    let value = map.value(x, y);
+                    match format {
+                        SampleFormat::F32 => writer.write_all(&(value as f32).to_le_bytes())?,
+                        SampleFormat::U16 => writer.write_all(&(((value - min) * scale).round() as u16).to_le_bytes())?,
+                    }
+                }
+            }
+        }
+    }
+    writer.flush()
+}
+
+/// Loaded tiles of a `TiledElevationMap`, the least recently used ones are dropped to stay within the budget.
+struct TileCache {
+    tiles: HashMap<usize, (Arc<Vec<f32>>, u64)>,
+    /// counter for the last use of the tiles
+    clock: u64,
+    memory: usize,
+    budget: usize,
+    /// tiles which couldn't be read, their errors are only reported once
+    failed: HashSet<usize>,
+}
+
+impl TileCache {
+    fn get(&mut self, index: usize) -> Option<Arc<Vec<f32>>> {
+        self.clock += 1;
+        let (tile, last_used) = self.tiles.get_mut(&index)?;
+        *last_used = self.clock;
+        Some(tile.clone())
+    }
+
+    fn insert(&mut self, index: usize, tile: Arc<Vec<f32>>) {
+        // another thread may have read the same tile in the meantime
+        if self.tiles.contains_key(&index) {
+            return;
+        }
+        let memory = tile.len() * std::mem::size_of::<f32>();
+        while self.memory + memory > self.budget {
+            let Some(oldest) = self.tiles.iter().min_by_key(|(_, (_, last_used))| *last_used).map(|(index, _)| *index) else { break };
+            let (tile, _) = self.tiles.remove(&oldest).unwrap();
+            self.memory -= tile.len() * std::mem::size_of::<f32>();
+        }
+        self.memory += memory;
+        self.tiles.insert(index, (tile, self.clock));
+    }
+}
+
+/// Elevation map in a tiled file (see `write_tiled_elevation_map`), whose tiles are read when their
+/// values are needed, e.g. while the chunks of the terrain are created from it.
+/// Loaded tiles are kept within a memory budget (in bytes, but at least one tile), dropping the least recently used tiles.
+/// It can be used like an `ElevationMap` through the `Heightmap` trait, also from several threads.
+pub struct TiledElevationMap {
+    header: TiledHeader,
+    offsets: Vec<u64>,
+    file: Mutex<File>,
+    cache: Mutex<TileCache>,
+}
+
+impl TiledElevationMap {
+    /// Opens a tiled elevation map, reading its header and tile index.
+    pub fn open(path: impl AsRef<Path>, budget: usize) -> Result<Self, TerrainError> {
+        let mut file = File::open(path)?;
+        let mut bytes = [0u8; TiledHeader::SIZE];
+        file.read_exact(&mut bytes)?;
+        let header = TiledHeader::from_bytes(&bytes)?;
+        let (tiles_x, tiles_y) = header.tiles();
+        // the index has to fit into the file before it's allocated, a broken header could claim any number of tiles
+        let length = file.metadata()?.len();
+        let index_size = tiles_x.checked_mul(tiles_y).and_then(|tiles| tiles.checked_mul(8))
+            .filter(|size| (*size as u64).checked_add(TiledHeader::SIZE as u64).is_some_and(|end| end <= length))
+            .ok_or_else(|| TerrainError::Decode(format!("the tile index of {tiles_x}x{tiles_y} tiles is beyond the end of the file").into()))?;
+        let mut index = vec![0u8; index_size];
+        file.read_exact(&mut index)?;
+        let offsets: Vec<u64> = index.chunks_exact(8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap())).collect();
+
+        // every tile has to be within the file, so reading tiles only fails on I/O errors
+        for (i, offset) in offsets.iter().enumerate() {
+            let (tile_width, tile_height) = header.tile_extent(i % tiles_x, i / tiles_x);
+            let end = offset.checked_add((tile_width * tile_height * header.format.bytes()) as u64);
+            if end.is_none_or(|end| end > length) {
+                return Err(TerrainError::Decode(format!("tile {i} is beyond the end of the file").into()));
+            }
+        }
+        let cache = TileCache { tiles: HashMap::new(), clock: 0, memory: 0, budget, failed: HashSet::new() };
+        Ok(Self { header, offsets, file: Mutex::new(file), cache: Mutex::new(cache) })
+    }
+
+    pub fn header(&self) -> &TiledHeader {
+        &self.header
+    }
+
+    /// Returns the number of loaded tiles and the memory used by them.
+    pub fn memory(&self) -> (usize, usize) {
+        let cache = self.cache.lock().unwrap();
+        (cache.tiles.len(), cache.memory)
+    }
+
+    /// Returns the memory budget of the loaded tiles.
+    pub fn budget(&self) -> usize {
+        self.cache.lock().unwrap().budget
+    }
+
+    /// Reads the whole map into an `ElevationMap`.
+    pub fn to_elevation_map(&self) -> ElevationMap {
+        let (width, height) = self.header.size;
+        let values = self.values(0..=width as isize - 1, 0..=height as isize - 1);
+        ElevationMap::new_with_data(width, height, values).with_edge_mode(self.header.edge_mode)
+    }
+
+    /// Returns the values at the positions `columns` and `rows` (sampled according to the edge mode) row by row.
+    /// The tile is only looked up when the next value is in another tile, instead of for every value.
+    fn values(&self, columns: RangeInclusive<isize>, rows: RangeInclusive<isize>) -> Vec<f64> {
+        let mut values = Vec::with_capacity(columns.clone().count() * rows.clone().count());
+        let mut current: Option<(usize, Option<Arc<Vec<f32>>>)> = None;
+        for y in rows {
+            for x in columns.clone() {
+                let Some((x, y)) = self.resolve(x, y) else {
+                    values.push(self.get_value(x, y));
+                    continue;
+                };
+                let index = self.tile_index(x, y);
+                if current.as_ref().is_none_or(|(current, _)| *current != index) {
+                    current = Some((index, self.loaded_tile(index)));
+                }
+                let tile = current.as_ref().and_then(|(_, tile)| tile.as_deref());
+                values.push(tile.map_or(0.0, |tile| self.tile_value(tile, x, y)));
+            }
+        }
+        values
+    }
+
+    /// Returns the index of the tile containing the texel (x, y).
+    fn tile_index(&self, x: usize, y: usize) -> usize {
+        x / self.header.tile_size + y / self.header.tile_size * self.header.tiles().0
+    }
+
+    /// Returns the value of the texel (x, y) within its tile.
+    fn tile_value(&self, tile: &[f32], x: usize, y: usize) -> f64 {
+        let tile_size = self.header.tile_size;
+        let tile_width = self.header.tile_extent(x / tile_size, y / tile_size).0;
+        tile[x % tile_size + (y % tile_size) * tile_width] as f64
+    }
+
+    /// Returns the tile with the given index like `tile`, but reports I/O errors instead of returning them,
+    /// once for every tile (reading a chunk samples a failing tile many times).
+    fn loaded_tile(&self, index: usize) -> Option<Arc<Vec<f32>>> {
+        self.tile(index)
+            .inspect_err(|err| {
+                if self.cache.lock().unwrap().failed.insert(index) {
+                    eprintln!("couldn't read tile {index} of the tiled elevation map: {err}");
+                }
+            })
+            .ok()
+    }
+
+    /// Returns the tile with the given index, reading it from the file if it isn't loaded.
+    fn tile(&self, index: usize) -> io::Result<Arc<Vec<f32>>> {
+        if let Some(tile) = self.cache.lock().unwrap().get(index) {
+            return Ok(tile);
+        }
+        let tiles_x = self.header.tiles().0;
+        let (tile_width, tile_height) = self.header.tile_extent(index % tiles_x, index / tiles_x);
+        let mut bytes = vec![0u8; tile_width * tile_height * self.header.format.bytes()];
+        {
+            let mut file = self.file.lock().unwrap();
+            file.seek(SeekFrom::Start(self.offsets[index]))?;
+            file.read_exact(&mut bytes)?;
+        }
+        let (min, max) = self.header.height_range;
+        let tile: Vec<f32> = match self.header.format {
+            SampleFormat::F32 => bytes.chunks_exact(4).map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap())).collect(),
+            SampleFormat::U16 => bytes.chunks_exact(2)
+                .map(|bytes| (min + u16::from_le_bytes(bytes.try_into().unwrap()) as f64 / 65535.0 * (max - min)) as f32)
+                .collect(),
+        };
+        let tile = Arc::new(tile);
+        self.cache.lock().unwrap().insert(index, tile.clone());
+        Ok(tile)
+    }
+}
+
+impl Heightmap for TiledElevationMap {
+    fn size(&self) -> (usize, usize) {
+        self.header.size
+    }
+
+    fn edge_mode(&self) -> EdgeMode {
+        self.header.edge_mode
+    }
+
+    fn value(&self, x: usize, y: usize) -> f64 {
+        self.loaded_tile(self.tile_index(x, y)).map_or(0.0, |tile| self.tile_value(&tile, x, y))
+    }
+
+    fn region(&self, columns: RangeInclusive<isize>, rows: RangeInclusive<isize>) -> ElevationMap {
+        let size = (columns.clone().count(), rows.clone().count());
+        ElevationMap::new_with_data(size.0, size.1, self.values(columns, rows)).with_edge_mode(EdgeMode::Clamp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_map() -> ElevationMap {
+        let (width, height) = (37, 21);
+        let values = (0..width * height).map(|i| ((i * 13) % 29) as f64 * 0.125 - 1.0).collect();
+        ElevationMap::new_with_data(width, height, values).with_edge_mode(EdgeMode::SeaLevel(-2.0))
+    }
+
+    #[test]
+    fn tiled_maps_read_like_the_original() {
+        let map = test_map();
+        let dir = std::env::temp_dir().join(format!("tiled-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for (format, tolerance) in [(SampleFormat::F32, 1e-6), (SampleFormat::U16, 3.5 / 65535.0)] {
+            let path = dir.join(format!("{format:?}.thm"));
+            write_tiled_elevation_map(&map, &path, 8, format).unwrap();
+            let tiled = TiledElevationMap::open(&path, 1 << 20).unwrap();
+            assert_eq!(tiled.header().tiles(), (5, 3));
+            assert_eq!((Heightmap::size(&tiled), Heightmap::edge_mode(&tiled)), (map.size(), map.edge_mode()));
+            let loaded = tiled.to_elevation_map();
+            for (loaded, original) in loaded.values().iter().zip(map.values()) {
+                assert!((loaded - original).abs() <= tolerance, "{format:?}: {loaded} != {original}");
+            }
+            // a region across tiles and beyond the border, like the one of a chunk
+            let region = Heightmap::region(&tiled, -2..=17, 5..=22);
+            assert_eq!(region.size(), (20, 18));
+            for (i, value) in region.values().iter().enumerate() {
+                let original = map.get_value(i as isize % 20 - 2, i as isize / 20 + 5);
+                assert!((value - original).abs() <= tolerance, "{format:?}: {value} != {original}");
+            }
+            // outside of the map and between the texels
+            assert_eq!(Heightmap::get_value(&tiled, -1, 3), -2.0);
+            assert!((Heightmap::sample(&tiled, 20.3, 7.9) - map.sample(20.3, 7.9)).abs() <= tolerance);
+        }
+
+        // not a tiled map, or cut off
+        let path = dir.join("broken.thm");
+        std::fs::write(&path, b"something else").unwrap();
+        assert!(TiledElevationMap::open(&path, 1 << 20).is_err());
+        let bytes = std::fs::read(dir.join("F32.thm")).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 4]).unwrap();
+        assert!(matches!(TiledElevationMap::open(&path, 1 << 20), Err(TerrainError::Decode(_))));
+        // a header claiming far more tiles than the file holds
+        let mut huge = bytes[..TiledHeader::SIZE].to_vec();
+        huge[12..24].copy_from_slice(&[u32::MAX.to_le_bytes(), u32::MAX.to_le_bytes(), 1u32.to_le_bytes()].concat());
+        std::fs::write(&path, huge).unwrap();
+        assert!(matches!(TiledElevationMap::open(&path, 1 << 20), Err(TerrainError::Decode(_))));
+        // a tile offset which overflows
+        let mut overflowing = bytes.clone();
+        overflowing[TiledHeader::SIZE..TiledHeader::SIZE + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, overflowing).unwrap();
+        assert!(matches!(TiledElevationMap::open(&path, 1 << 20), Err(TerrainError::Decode(_))));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn tiles_are_paged_within_the_budget() {
+        let map = test_map();
+        let path = std::env::temp_dir().join(format!("tiled-paging-{}.thm", std::process::id()));
+        write_tiled_elevation_map(&map, &path, 8, SampleFormat::F32).unwrap();
+        // room for two full tiles
+        let tiled = TiledElevationMap::open(&path, 2 * 8 * 8 * 4).unwrap();
+        assert_eq!(tiled.memory(), (0, 0));
+        assert_eq!(tiled.value(1, 1), map.get_value(1, 1));
+        assert_eq!(tiled.value(9, 1), map.get_value(9, 1));
+        assert_eq!(tiled.memory(), (2, 2 * 8 * 8 * 4));
+        // the first tile was used last, so the second one is dropped for the third one
+        assert_eq!(tiled.value(2, 2), map.get_value(2, 2));
+        assert_eq!(tiled.value(17, 1), map.get_value(17, 1));
+        assert_eq!(tiled.memory().0, 2);
+        assert!(tiled.cache.lock().unwrap().tiles.contains_key(&0));
+        assert!(!tiled.cache.lock().unwrap().tiles.contains_key(&1));
+        // reading the whole map never exceeds the budget
+        let loaded = tiled.to_elevation_map();
+        assert_eq!(loaded.values(), map.values().iter().map(|value| *value as f32 as f64).collect::<Vec<_>>());
+        assert!(tiled.memory().1 <= tiled.budget());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn unreadable_tiles_are_remembered() {
+        let map = test_map();
+        let path = std::env::temp_dir().join(format!("tiled-failing-{}.thm", std::process::id()));
+        write_tiled_elevation_map(&map, &path, 8, SampleFormat::F32).unwrap();
+        let tiled = TiledElevationMap::open(&path, 1 << 20).unwrap();
+        // the file is cut off after it was opened, so the last tile can't be read anymore
+        let length = std::fs::metadata(&path).unwrap().len();
+        File::options().write(true).open(&path).unwrap().set_len(length - 4).unwrap();
+        let region = Heightmap::region(&tiled, 32..=36, 16..=20);
+        assert!(region.values().iter().all(|value| *value == 0.0));
+        assert_eq!(tiled.value(36, 20), 0.0);
+        assert_eq!(tiled.value(0, 0), map.get_value(0, 0));
+        assert_eq!(tiled.cache.lock().unwrap().failed, HashSet::from([14]));
+        std::fs::remove_file(path).unwrap();
+    }
+}